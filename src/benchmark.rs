@@ -0,0 +1,96 @@
+// src/benchmark.rs
+
+use anyhow::{Context, Result};
+use comfy_table::{presets::UTF8_FULL, Cell, Table};
+use ipnet::IpNet;
+use rayon::prelude::*;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Measure scan throughput for a sweep of thread counts and timeouts
+#[derive(Debug, Clone, clap::Args)]
+pub struct BenchmarkArgs {
+    /// The subnet to scan in CIDR notation (e.g., 192.168.1.0/24)
+    #[arg(long)]
+    subnet: String,
+
+    /// The port to scan for
+    #[arg(short, long, default_value_t = 7890)]
+    port: u16,
+
+    /// Comma-separated scan thread counts to try (defaults to the CPU count)
+    #[arg(long, value_delimiter = ',', value_name = "N,N,...")]
+    sweep_threads: Vec<usize>,
+
+    /// Comma-separated connection timeouts to try, e.g. `100ms,1s` (bare numbers are milliseconds)
+    #[arg(long, value_delimiter = ',', value_name = "DURATION,...", default_value = "200ms", value_parser = crate::parse_millis_or_duration)]
+    sweep_timeouts: Vec<Duration>,
+
+    /// Confirm you are authorized to scan a public (non-private) subnet
+    #[arg(long)]
+    i_understand: bool,
+}
+
+struct BenchmarkRun {
+    threads: usize,
+    timeout: Duration,
+    elapsed: Duration,
+    open: usize,
+}
+
+pub fn run(args: &BenchmarkArgs) -> Result<()> {
+    let network: IpNet = args.subnet.parse().with_context(|| format!("Invalid subnet: {}", args.subnet))?;
+    crate::confirm_public_scan(&network, args.i_understand)?;
+    let hosts: Vec<IpAddr> = network.hosts().collect();
+    let thread_counts = if args.sweep_threads.is_empty() {
+        vec![rayon::current_num_threads()]
+    } else {
+        args.sweep_threads.clone()
+    };
+
+    let mut runs = Vec::new();
+    for &threads in &thread_counts {
+        for &timeout in &args.sweep_timeouts {
+            println!("Benchmarking {} threads, {} timeout over {} hosts...", threads, humantime::format_duration(timeout), hosts.len());
+            runs.push(scan_once(&hosts, args.port, threads, timeout)?);
+        }
+    }
+
+    display_runs(hosts.len(), &runs);
+    Ok(())
+}
+
+fn scan_once(hosts: &[IpAddr], port: u16, threads: usize, timeout: Duration) -> Result<BenchmarkRun> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+    let open = AtomicUsize::new(0);
+
+    let start_time = Instant::now();
+    pool.install(|| {
+        hosts.par_iter().for_each(|&ip| {
+            if TcpStream::connect_timeout(&SocketAddr::new(ip, port), timeout).is_ok() {
+                open.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    });
+
+    Ok(BenchmarkRun { threads, timeout, elapsed: start_time.elapsed(), open: open.into_inner() })
+}
+
+fn display_runs(host_count: usize, runs: &[BenchmarkRun]) {
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(vec!["Threads", "Timeout", "Elapsed", "Open", "Hosts/sec"]);
+
+    for run in runs {
+        let hosts_per_sec = host_count as f64 / run.elapsed.as_secs_f64().max(f64::EPSILON);
+        table.add_row(vec![
+            Cell::new(run.threads),
+            Cell::new(humantime::format_duration(run.timeout)),
+            Cell::new(format!("{:.2} s", run.elapsed.as_secs_f64())),
+            Cell::new(run.open),
+            Cell::new(format!("{:.0}", hosts_per_sec)),
+        ]);
+    }
+
+    println!("{table}");
+}
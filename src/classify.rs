@@ -0,0 +1,71 @@
+// src/classify.rs
+
+use serde::{Deserialize, Serialize};
+
+/// A best-effort guess at what kind of network a proxy exits from.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionType {
+    Datacenter,
+    Residential,
+    Mobile,
+    /// Nothing was known about the network (e.g. the geo lookup timed out)
+    Unknown,
+}
+
+/// Well-known hosting and cloud ASNs.
+const DATACENTER_ASNS: &[&str] = &[
+    "AS13335",  // Cloudflare
+    "AS14061",  // DigitalOcean
+    "AS14618",  // Amazon
+    "AS15169",  // Google
+    "AS16276",  // OVH
+    "AS16509",  // Amazon
+    "AS20473",  // Vultr / Choopa
+    "AS24940",  // Hetzner
+    "AS31898",  // Oracle
+    "AS45102",  // Alibaba
+    "AS51167",  // Contabo
+    "AS60781",  // Leaseweb
+    "AS63949",  // Linode / Akamai
+    "AS8075",   // Microsoft
+    "AS9009",   // M247
+    "AS132203", // Tencent
+];
+
+const DATACENTER_KEYWORDS: &[&str] = &[
+    "hosting", "host", "cloud", "datacenter", "data center", "server", "colo", "vps", "amazon", "aws",
+    "google", "microsoft", "azure", "digitalocean", "ovh", "hetzner", "linode", "vultr", "choopa",
+    "alibaba", "tencent", "oracle", "leaseweb", "contabo", "m247",
+];
+
+const MOBILE_KEYWORDS: &[&str] = &["mobile", "wireless", "cellular", "lte", "5g", "gsm"];
+
+/// Classifies a network from ip-api's ISP/org/AS strings. The provider's own `mobile`
+/// and `hosting` flags win when present; otherwise known ASNs, then keywords, decide.
+/// Anything that looks like neither a datacenter nor a mobile carrier is treated as residential.
+pub fn classify(isp: Option<&str>, org: Option<&str>, asn: Option<&str>, mobile: Option<bool>, hosting: Option<bool>) -> ConnectionType {
+    if [isp, org, asn].iter().all(Option::is_none) && mobile.is_none() && hosting.is_none() {
+        return ConnectionType::Unknown;
+    }
+    if mobile == Some(true) {
+        return ConnectionType::Mobile;
+    }
+    if hosting == Some(true) {
+        return ConnectionType::Datacenter;
+    }
+
+    let as_number = asn.and_then(|asn| asn.split_whitespace().next()).unwrap_or("");
+    if DATACENTER_ASNS.contains(&as_number) {
+        return ConnectionType::Datacenter;
+    }
+
+    let haystack = [isp, org, asn].iter().flatten().map(|s| s.to_lowercase()).collect::<Vec<_>>().join(" ");
+    if MOBILE_KEYWORDS.iter().any(|keyword| haystack.contains(keyword)) {
+        ConnectionType::Mobile
+    } else if DATACENTER_KEYWORDS.iter().any(|keyword| haystack.contains(keyword)) {
+        ConnectionType::Datacenter
+    } else {
+        ConnectionType::Residential
+    }
+}
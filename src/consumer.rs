@@ -0,0 +1,177 @@
+// src/consumer.rs
+
+use crate::keys::KeyCommand;
+use crate::producer::ProxyTarget;
+use crate::scheduler::{TestOutcome, TestScheduler};
+use indicatif::ProgressBar;
+use tokio::sync::mpsc;
+use tokio::task::JoinError;
+use tokio::time::Interval;
+
+/// The parts of the scheduler the consumer loop waits on.
+pub(crate) trait TestQueue {
+    type Outcome;
+
+    fn has_capacity(&self) -> bool;
+    fn is_empty(&self) -> bool;
+    async fn join_next(&mut self) -> Option<Self::Outcome>;
+}
+
+impl TestQueue for TestScheduler {
+    type Outcome = Result<TestOutcome, JoinError>;
+
+    fn has_capacity(&self) -> bool {
+        TestScheduler::has_capacity(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        TestScheduler::is_empty(self)
+    }
+
+    async fn join_next(&mut self) -> Option<Self::Outcome> {
+        TestScheduler::join_next(self).await
+    }
+}
+
+/// Loop state besides the work itself: the key reader, if any, the progress message to
+/// restore while testing is paused, and the `--autosave-interval` timer.
+pub struct Controls {
+    pub keys: Option<mpsc::UnboundedReceiver<KeyCommand>>,
+    pub paused_message: Option<String>,
+    pub autosave: Option<Interval>,
+}
+
+/// What the consumer loop handles next.
+pub enum Step<O> {
+    /// An open address from the producer, to be submitted for testing
+    Target(ProxyTarget),
+    /// A test finished
+    Finished(O),
+    /// `--autosave-interval` is due
+    Autosave,
+    /// The user pressed `q`
+    Quit,
+    /// Nothing is running and the producer is done (or testing is paused with the
+    /// channel empty); the caller flushes, starts a second pass or stops
+    Idle,
+}
+
+/// Waits for the next thing the consumer loop must act on. Pause toggles are handled
+/// here, so callers only see work.
+pub async fn next_step<Q: TestQueue>(rx: &mut mpsc::Receiver<ProxyTarget>, queue: &mut Q, controls: &mut Controls, pb: &ProgressBar) -> Step<Q::Outcome> {
+    loop {
+        // Once both the producer and the queue are drained, keys must not keep the loop alive
+        let work_pending = !(rx.is_closed() && rx.is_empty() && queue.is_empty());
+        tokio::select! {
+            command = next_key(&mut controls.keys), if controls.keys.is_some() && (work_pending || controls.paused_message.is_some()) => {
+                match command {
+                    // The key reader is gone. Resume, or a pause would disable the receive
+                    // branch and let `else` end the loop with addresses still queued
+                    None => {
+                        controls.keys = None;
+                        if let Some(message) = controls.paused_message.take() {
+                            pb.set_message(message);
+                        }
+                    }
+                    Some(KeyCommand::TogglePause) => match controls.paused_message.take() {
+                        Some(message) => pb.set_message(message),
+                        None => {
+                            controls.paused_message = Some(pb.message());
+                            pb.set_message("PAUSED (space to resume, q to stop)");
+                        }
+                    },
+                    Some(KeyCommand::Quit) => return Step::Quit,
+                }
+            },
+            // Like the key branch, only while work remains, so it can't hold off `else`
+            _ = next_tick(&mut controls.autosave), if controls.autosave.is_some() && work_pending => return Step::Autosave,
+            // While paused no new tests start; running ones still finish
+            Some(target) = rx.recv(), if controls.paused_message.is_none() && queue.has_capacity() => return Step::Target(target),
+            Some(outcome) = queue.join_next(), if !queue.is_empty() => return Step::Finished(outcome),
+            // Reached only when every branch above is disabled or came back empty. `recv`
+            // yields `None` only once the channel is closed *and* drained, so a burst sent
+            // right before the producer exits is still received before we get here
+            else => return Step::Idle,
+        }
+    }
+}
+
+/// Resolves to the next keyboard command; only polled while a listener exists.
+async fn next_key(keys: &mut Option<mpsc::UnboundedReceiver<KeyCommand>>) -> Option<KeyCommand> {
+    match keys {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves at the next `--autosave-interval` tick; only polled while autosaving.
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => drop(interval.tick().await),
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// Tests that finish the moment they are submitted
+    struct InstantQueue;
+
+    impl TestQueue for InstantQueue {
+        type Outcome = ();
+
+        fn has_capacity(&self) -> bool {
+            true
+        }
+
+        fn is_empty(&self) -> bool {
+            true
+        }
+
+        async fn join_next(&mut self) -> Option<()> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn burst_before_close_is_drained_after_key_reader_goes_away_while_paused() {
+        const N: u16 = 50;
+        let (tx, mut rx) = mpsc::channel(usize::from(N));
+        for port in 1..=N {
+            tx.send(ProxyTarget::from(SocketAddr::from(([127, 0, 0, 1], port)))).await.unwrap();
+        }
+        drop(tx);
+        let (keys_tx, keys) = mpsc::unbounded_channel();
+        drop(keys_tx);
+        let mut controls = Controls { keys: Some(keys), paused_message: Some("testing".to_string()), autosave: None };
+
+        let mut seen = Vec::new();
+        loop {
+            match next_step(&mut rx, &mut InstantQueue, &mut controls, &ProgressBar::hidden()).await {
+                Step::Target(target) => seen.push(target.addr.port()),
+                Step::Idle => break,
+                Step::Finished(()) | Step::Autosave | Step::Quit => unreachable!(),
+            }
+        }
+        assert_eq!(seen, (1..=N).collect::<Vec<_>>());
+        assert!(controls.paused_message.is_none());
+    }
+
+    #[tokio::test]
+    async fn pause_then_resume_still_delivers_queued_target() {
+        let (tx, mut rx) = mpsc::channel(1);
+        tx.send(ProxyTarget::from(SocketAddr::from(([127, 0, 0, 1], 80)))).await.unwrap();
+        drop(tx);
+        let (keys_tx, keys) = mpsc::unbounded_channel();
+        keys_tx.send(KeyCommand::TogglePause).unwrap();
+        keys_tx.send(KeyCommand::TogglePause).unwrap();
+        let mut controls = Controls { keys: Some(keys), paused_message: None, autosave: None };
+
+        // The first toggle pauses and the second resumes, so the target still comes through
+        let step = next_step(&mut rx, &mut InstantQueue, &mut controls, &ProgressBar::hidden()).await;
+        assert!(matches!(step, Step::Target(target) if target.addr.port() == 80));
+    }
+}
@@ -0,0 +1,88 @@
+// src/diff.rs
+
+use crate::{producer, ProxyResult};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+/// The columns `--diff` and `--new-only` need from a results CSV. Older files without a
+/// `Port` column carry `ip:port` in the address column instead.
+#[derive(Debug, Deserialize)]
+struct PreviousRecord {
+    #[serde(rename = "IP Address")]
+    ip_address: String,
+    #[serde(rename = "Port")]
+    port: Option<u16>,
+}
+
+/// Loads the proxies listed in a results CSV (a previous run or a `--new-only` master
+/// list), keyed by IP:port. A `.gz` file, as written by `--gzip-output`, is decompressed.
+pub fn load_previous(path: &Path) -> Result<BTreeSet<SocketAddr>> {
+    let reader = producer::open_input(path, false).with_context(|| format!("Failed to open results list {}", path.display()))?;
+    let mut rdr = csv::Reader::from_reader(reader);
+    let mut previous = BTreeSet::new();
+    for record in rdr.deserialize() {
+        let record: PreviousRecord = record.with_context(|| format!("Malformed row in {}", path.display()))?;
+        let addr = match (record.ip_address.parse::<IpAddr>(), record.port) {
+            (Ok(ip), Some(port)) => Some(SocketAddr::new(ip, port)),
+            _ => record.ip_address.parse::<SocketAddr>().ok(),
+        };
+        previous.extend(addr);
+    }
+    Ok(previous)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Change {
+    Added,
+    Removed,
+    Unchanged,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffRecord {
+    #[serde(rename = "Change")]
+    change: Change,
+    #[serde(rename = "IP Address")]
+    ip_address: IpAddr,
+    #[serde(rename = "Port")]
+    port: u16,
+}
+
+/// How the current run's working set differs from a previous one.
+#[derive(Debug, Default)]
+pub struct PoolDiff {
+    pub added: BTreeSet<SocketAddr>,
+    pub removed: BTreeSet<SocketAddr>,
+    pub unchanged: BTreeSet<SocketAddr>,
+}
+
+impl PoolDiff {
+    pub fn compute(previous: &BTreeSet<SocketAddr>, current: &[ProxyResult]) -> Self {
+        let current: BTreeSet<SocketAddr> = current.iter().map(|result| SocketAddr::new(result.ip_address, result.port)).collect();
+        Self {
+            added: current.difference(previous).copied().collect(),
+            removed: previous.difference(&current).copied().collect(),
+            unchanged: current.intersection(previous).copied().collect(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!("Compared with the previous run: {} new, {} gone, {} still working.", self.added.len(), self.removed.len(), self.unchanged.len())
+    }
+
+    /// One row per address with its change, additions first.
+    pub fn write_csv(&self, writer: impl Write) -> Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for (change, addrs) in [(Change::Added, &self.added), (Change::Removed, &self.removed), (Change::Unchanged, &self.unchanged)] {
+            for addr in addrs {
+                writer.serialize(DiffRecord { change, ip_address: addr.ip(), port: addr.port() })?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
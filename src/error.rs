@@ -0,0 +1,48 @@
+// src/error.rs
+
+use std::path::PathBuf;
+
+/// Everything the library API can fail with.
+#[derive(Debug, thiserror::Error)]
+pub enum ScannerError {
+    /// A network or prefix that doesn't parse, or that the API can't use
+    #[error("{input}: {reason}")]
+    InvalidCidr { input: String, reason: String },
+
+    /// A malformed port list
+    #[error("{0}")]
+    InvalidPorts(String),
+
+    /// A `LAT,LON` point that doesn't parse or isn't on the globe
+    #[error("{0}")]
+    InvalidCoordinates(String),
+
+    #[error("Failed to read {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A request that never got an answer
+    #[error("Request failed")]
+    Network(#[source] reqwest::Error),
+
+    /// A request that ran past the client's timeout
+    #[error("Request timed out")]
+    Timeout(#[source] reqwest::Error),
+
+    /// A geo provider that answered with an error of its own
+    #[error("Geo API error: {0}")]
+    Geo(String),
+}
+
+impl From<reqwest::Error> for ScannerError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout(error)
+        } else {
+            Self::Network(error)
+        }
+    }
+}
@@ -0,0 +1,39 @@
+// src/events.rs
+
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+
+/// Machine-readable progress events written to stderr by `--progress-json`,
+/// one JSON object per line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// `port` is the first of `ports`, kept for consumers written before multi-port scans
+    ScanStarted { source: &'a str, port: u16, ports: &'a [u16] },
+    HostOpen {
+        address: SocketAddr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        banner: Option<&'a str>,
+    },
+    ProxyFound { address: SocketAddr },
+    ProxyTested {
+        address: SocketAddr,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        response_time_ms: Option<u128>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        location: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Finished { working: usize },
+}
+
+pub fn emit(event: &Event) {
+    if let Ok(line) = serde_json::to_string(event) {
+        // Lock so lines from concurrent scan threads never interleave
+        let mut stderr = std::io::stderr().lock();
+        let _ = writeln!(stderr, "{}", line);
+    }
+}
@@ -0,0 +1,119 @@
+// src/geo.rs
+
+use crate::ScannerError;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+pub const GEO_API_HOST: &str = "http://ip-api.com";
+
+/// ip-api's `fields=` bitmask selecting every field it offers, used by `--geo-full`.
+pub const GEO_ALL_FIELDS: u32 = 66_846_719;
+
+/// Second, independent provider used by `--validate-geo` to cross-check countries.
+const SECONDARY_GEO_API_HOST: &str = "https://ipwho.is";
+
+// --- Distance ---
+
+/// A point on the globe for `--near`, written `LAT,LON` in decimal degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl std::str::FromStr for GeoPoint {
+    type Err = ScannerError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = ScannerError::InvalidCoordinates;
+        let (lat, lon) = value.split_once(',').ok_or_else(|| invalid(format!("expected LAT,LON, got {:?}", value)))?;
+        let lat: f64 = lat.trim().parse().map_err(|_| invalid(format!("invalid latitude {:?}", lat.trim())))?;
+        let lon: f64 = lon.trim().parse().map_err(|_| invalid(format!("invalid longitude {:?}", lon.trim())))?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(invalid(format!("{},{} is not on the globe", lat, lon)));
+        }
+        Ok(Self { lat, lon })
+    }
+}
+
+impl GeoPoint {
+    /// Great-circle distance in kilometres (haversine on a spherical Earth).
+    pub fn distance_km(self, other: GeoPoint) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon - self.lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+// --- Provider Responses ---
+
+/// Everything ip-api can return. The default `/json` response carries a subset; the rest
+/// only appears when requested via `fields=` (see `GEO_ALL_FIELDS`).
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeoLocationResponse {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub continent: Option<String>,
+    pub continent_code: Option<String>,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
+    pub region: Option<String>,
+    pub region_name: Option<String>,
+    pub city: Option<String>,
+    pub district: Option<String>,
+    pub zip: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub timezone: Option<String>,
+    /// UTC offset in seconds
+    pub offset: Option<i32>,
+    pub currency: Option<String>,
+    pub isp: Option<String>,
+    pub org: Option<String>,
+    #[serde(rename = "as")]
+    pub asn: Option<String>,
+    pub asname: Option<String>,
+    pub reverse: Option<String>,
+    pub mobile: Option<bool>,
+    pub proxy: Option<bool>,
+    pub hosting: Option<bool>,
+    pub query: Option<String>,
+}
+
+impl GeoLocationResponse {
+    /// A successful lookup that learned nothing, for proxies whose geo response never arrived.
+    pub fn unknown() -> Self {
+        Self { status: "success".to_string(), ..Self::default() }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SecondaryGeoResponse {
+    success: bool,
+    country_code: Option<String>,
+    message: Option<String>,
+}
+
+// --- Lookups ---
+
+/// The URL `secondary_country_code` looks `ip` up at; also what its answers are cached under.
+pub fn secondary_lookup_url(ip: IpAddr) -> String {
+    format!("{}/{}", SECONDARY_GEO_API_HOST, ip)
+}
+
+/// Asks the secondary provider which country `ip` is in. This is a direct request,
+/// not one made through the proxy under test.
+pub async fn secondary_country_code(client: &reqwest::Client, ip: IpAddr) -> Result<Option<String>, ScannerError> {
+    let response = client.get(secondary_lookup_url(ip)).send().await?.json::<SecondaryGeoResponse>().await?;
+    if response.success {
+        Ok(response.country_code)
+    } else {
+        let err_msg = response.message.unwrap_or_else(|| "API error".to_string());
+        Err(ScannerError::Geo(format!("secondary provider: {}", err_msg)))
+    }
+}
@@ -0,0 +1,26 @@
+// src/geo_cache.rs
+
+use crate::ttl_cache::TtlCache;
+use anyhow::Result;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use subnet_scanner::geo;
+
+/// On-disk cache of direct geo lookups: the country code each answered with, keyed by
+/// the lookup URL so that different providers never answer for each other. Loaded with
+/// `--geo-cache` and saved at exit.
+pub type GeoCache = TtlCache<Option<String>>;
+
+/// `geo::secondary_country_code`, answered from the cache when it can be. Only successful
+/// lookups are cached.
+pub async fn secondary_country_code(client: &reqwest::Client, ip: IpAddr, cache: Option<&Mutex<GeoCache>>) -> Result<Option<String>> {
+    let url = geo::secondary_lookup_url(ip);
+    if let Some(country_code) = cache.and_then(|cache| cache.lock().unwrap().get(&url)) {
+        return Ok(country_code);
+    }
+    let country_code = geo::secondary_country_code(client, ip).await?;
+    if let Some(cache) = cache {
+        cache.lock().unwrap().insert(url, country_code.clone());
+    }
+    Ok(country_code)
+}
@@ -0,0 +1,80 @@
+// src/keys.rs
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// What a keypress asks the consumer loop to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCommand {
+    TogglePause,
+    Quit,
+}
+
+/// Keeps the terminal in raw mode while alive; dropping it stops the reader
+/// thread and restores the terminal.
+pub struct KeyListener {
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for KeyListener {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Starts reading single keypresses: space toggles pause, `q` (or Ctrl-C, which raw
+/// mode no longer turns into SIGINT) quits. Returns `None` if raw mode is unavailable.
+pub fn listen() -> Option<(KeyListener, mpsc::UnboundedReceiver<KeyCommand>)> {
+    terminal::enable_raw_mode().ok()?;
+    keep_output_processing();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::unbounded_channel();
+    let thread_done = done.clone();
+    // A plain thread rather than spawn_blocking, so the runtime never waits on it at exit
+    std::thread::spawn(move || {
+        while !thread_done.load(Ordering::Relaxed) {
+            if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+                continue;
+            }
+            let Ok(Event::Key(key)) = event::read() else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let command = match key.code {
+                KeyCode::Char(' ') => KeyCommand::TogglePause,
+                KeyCode::Char('q') => KeyCommand::Quit,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => KeyCommand::Quit,
+                _ => continue,
+            };
+            if tx.send(command).is_err() {
+                break;
+            }
+        }
+    });
+    Some((KeyListener { done }, rx))
+}
+
+/// Raw mode also disables output post-processing, which would make every log line
+/// start where the previous one ended. Only input needs to be raw, so turn it back on.
+#[cfg(unix)]
+fn keep_output_processing() {
+    // SAFETY: tcgetattr/tcsetattr only read and write the termios struct we own
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+            termios.c_oflag |= libc::OPOST | libc::ONLCR;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn keep_output_processing() {}
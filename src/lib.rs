@@ -0,0 +1,12 @@
+// src/lib.rs
+
+//! The parts of subnet_scanner that don't depend on its command line: port specs, NAT64
+//! address synthesis and geo lookups. Failures are reported as [`ScannerError`] so callers
+//! can tell them apart; the `subnet_scanner` binary itself works in `anyhow`.
+
+pub mod error;
+pub mod geo;
+pub mod nat64;
+pub mod ports;
+
+pub use error::ScannerError;
@@ -1,175 +1,1608 @@
 // src/main.rs
 
-use anyhow::Result;
+mod benchmark;
+mod classify;
+mod consumer;
+mod diff;
+mod events;
+mod geo_cache;
+mod keys;
+mod rawproxy;
+mod result_cache;
+mod output;
+mod producer;
+mod reformat;
+mod scheduler;
+mod ttl_cache;
+mod udp;
+
+use anyhow::{Context, Result};
 use clap::Parser;
+use classify::ConnectionType;
 use colored::Colorize;
-use comfy_table::{presets::UTF8_FULL, Cell, Table};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use ipnet::IpNet;
-use rayon::prelude::*;
+use output::{CsvSink, OutputFormat, OutputOptions, OutputSink, ScanMeta, TableSink, TableStyle};
+use producer::{Credentials, Producer, ProxyTarget, SkipList};
+use scheduler::TestScheduler;
 use reqwest::Proxy;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
 use dns_lookup::lookup_addr;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use events::Event;
+use subnet_scanner::{geo, nat64, ports};
+use geo::{GeoLocationResponse, GEO_ALL_FIELDS, GEO_API_HOST};
+use geo_cache::GeoCache;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet};
+use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tokio::task::{self, JoinSet};
+use tokio::task;
 
 // --- Structs for Data Handling ---
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct GeoLocationResponse {
-    status: String,
-    country: Option<String>,
-    city: Option<String>,
-    message: Option<String>,
+/// How working proxies are ordered in the results (`--rank-by`).
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum RankBy {
+    /// Fastest response first
+    Latency,
+    /// Weighted mix of latency, jitter and retries (see the --weight-* flags)
+    Score,
+}
+
+/// Line formats for `--emit-open`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum EmitFormat {
+    /// One `{"ip", "port", "open_at"}` object per line
+    Ndjson,
+}
+
+/// The proxy protocol a candidate is tested with.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    Http,
+    Socks5,
+}
+
+impl Protocol {
+    fn scheme(self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Socks5 => "socks5",
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ProxyResult {
     #[serde(rename = "IP Address")]
     ip_address: IpAddr,
+    #[serde(rename = "Port")]
+    port: u16,
+    #[serde(rename = "Protocol")]
+    protocol: Protocol,
     #[serde(rename = "Hostname")]
     hostname: String,
     #[serde(rename = "Response Time (ms)")]
     response_time_ms: u128,
+    #[serde(rename = "Jitter (ms)")]
+    jitter_ms: Option<u128>,
     #[serde(rename = "Location")]
     location: String,
+    #[serde(rename = "Country Code")]
+    country_code: Option<String>,
+    #[serde(rename = "Exit IP")]
+    exit_ip: Option<IpAddr>,
+    #[serde(rename = "ISP")]
+    isp: Option<String>,
+    #[serde(rename = "ASN")]
+    asn: Option<String>,
+    #[serde(rename = "Connection Type")]
+    connection_type: ConnectionType,
+    #[serde(rename = "Latency Overhead (ms)")]
+    latency_overhead_ms: Option<i128>,
+    #[serde(rename = "Geo Confident")]
+    geo_confident: Option<bool>,
+    #[serde(rename = "UDP Supported")]
+    udp_supported: Option<bool>,
+    #[serde(rename = "SNI OK")]
+    sni_ok: Option<bool>,
+    #[serde(rename = "Headers Preserved")]
+    headers_preserved: Option<bool>,
+    #[serde(rename = "IPv6 Capable")]
+    ipv6_capable: Option<bool>,
+    #[serde(rename = "Body Matched")]
+    body_matched: Option<bool>,
+    #[serde(rename = "Status Code")]
+    status_code: Option<u16>,
+    #[serde(rename = "Region")]
+    region: Option<String>,
+    #[serde(rename = "Latitude")]
+    lat: Option<f64>,
+    #[serde(rename = "Longitude")]
+    lon: Option<f64>,
+    #[serde(rename = "Timezone")]
+    timezone: Option<String>,
+    #[serde(rename = "Attempts")]
+    attempts: u32,
+    #[serde(rename = "Last Error")]
+    last_error: Option<String>,
+    #[serde(rename = "Banner")]
+    banner: Option<String>,
+    #[serde(rename = "Connect (ms)")]
+    connect_ms: Option<u128>,
+    #[serde(rename = "TTFB (ms)")]
+    ttfb_ms: Option<u128>,
+    #[serde(rename = "Total (ms)")]
+    total_ms: Option<u128>,
+    /// When the test that produced this result finished, RFC 3339 in UTC
+    #[serde(rename = "Discovered At", default)]
+    discovered_at: Option<String>,
+    /// Per-proxy credentials from the input list; kept out of CSV/JSON on purpose
+    #[serde(skip)]
+    credentials: Option<Credentials>,
+    /// The complete geo response, kept with --geo-full for the JSON output
+    #[serde(skip)]
+    geo: Option<GeoLocationResponse>,
+    /// Response headers kept with --capture-headers, lowercased names; JSON output only
+    #[serde(skip)]
+    headers: Option<BTreeMap<String, String>>,
+}
+
+/// The test reached its URL but was served a block or CAPTCHA page (`--block-signature`).
+/// Kept apart from ordinary failures: the proxy works, the site just refuses it.
+#[derive(Debug)]
+struct Blocked {
+    signature: String,
+    /// Replayed from `--result-cache` rather than seen in this run
+    cached: bool,
+}
+
+impl std::fmt::Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "served a block page (matched {:?})", self.signature)?;
+        if self.cached {
+            write!(f, " (cached)")?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct ProxyInputRecord {
+impl std::error::Error for Blocked {}
+
+/// A row of the `--fail-output` CSV.
+#[derive(Debug, Serialize)]
+struct FailedTest<'a> {
     #[serde(rename = "IP Address")]
-    ip_address: String,
+    ip_address: IpAddr,
+    #[serde(rename = "Port")]
+    port: u16,
+    #[serde(rename = "Attempts")]
+    attempts: u32,
+    #[serde(rename = "Last Error")]
+    last_error: Option<&'a str>,
+    #[serde(rename = "Banner")]
+    banner: Option<&'a str>,
+}
+
+impl<'a> From<&'a ProxyTarget> for FailedTest<'a> {
+    fn from(target: &'a ProxyTarget) -> Self {
+        Self {
+            ip_address: target.addr.ip(),
+            port: target.addr.port(),
+            attempts: target.attempts,
+            last_error: target.last_error.as_deref(),
+            banner: target.banner.as_deref(),
+        }
+    }
+}
+
+/// IPv6-only endpoint fetched through each proxy by `--test-ipv6`.
+const IPV6_TEST_URL: &str = "http://api6.ipify.org";
+
+/// Method `--check-headers` sends to the echo endpoint; not GET, so proxies that rewrite
+/// methods are caught too.
+const ECHO_METHOD: &str = "PUT";
+
+/// Headers `--check-headers` expects the echo endpoint to reflect unchanged. The token is
+/// fresh per test, so a cached echo can't pass.
+fn echo_probe_headers() -> Vec<(String, String)> {
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    vec![
+        ("X-Scanner-Probe".to_string(), format!("{:x}", nanos)),
+        ("X-Scanner-Probe-Detail".to_string(), "keep; me=intact".to_string()),
+    ]
+}
+
+/// Settings shared by every proxy test task.
+#[derive(Debug, Clone)]
+struct TestConfig {
+    timeout: Duration,
+    /// Pooled client for requests that don't go through a proxy (baseline, geo
+    /// cross-checks), so they can reuse connections across tests
+    direct_client: reqwest::Client,
+    url: String,
+    protocol: Protocol,
+    protocol_order: Option<Vec<Protocol>>,
+    baseline_ms: Option<u128>,
+    insecure: bool,
+    validate_geo: bool,
+    geo_cache: Option<Arc<Mutex<GeoCache>>>,
+    result_cache: Option<Arc<Mutex<result_cache::ResultCache>>>,
+    timings: bool,
+    probe_count: u32,
+    test_udp: bool,
+    sni: Option<rawproxy::SniCheck>,
+    /// `--echo-url` when `--check-headers` is on
+    echo_url: Option<String>,
+    geo_full: bool,
+    local_port_range: Option<rawproxy::PortRange>,
+    nat64: Option<nat64::Nat64Prefix>,
+    expect_body: Option<String>,
+    block_signatures: Vec<String>,
+    test_ipv6: bool,
+    geo_timeout: Option<Duration>,
+    stages: StageTimeouts,
+    /// Where `--connect-only` tunnels to; `None` sends real requests
+    connect_target: Option<rawproxy::TunnelTarget>,
+    head_request: bool,
+    lenient: bool,
+    accept_status: Option<StatusRanges>,
+    host_header: Option<String>,
+    /// Lowercased `--capture-headers` names; `None` keeps no headers
+    capture_headers: Option<Vec<String>>,
+    capture_all_headers: bool,
+    max_runtime_per_host: Option<Duration>,
+}
+
+impl TestConfig {
+    fn from_cli(cli: &Cli) -> Result<Self> {
+        // With a single probe there is no jitter, so --max-jitter would silently keep everything
+        if cli.max_jitter.is_some() && cli.probe_count < 2 {
+            anyhow::bail!("--max-jitter needs --probe-count of at least 2; a single probe has no jitter");
+        }
+        let path = cli.proxy_test_path.trim_start_matches('/');
+        let timeout = cli.test_timeout;
+        Ok(Self {
+            timeout,
+            direct_client: match (cli.nat64_prefix, cli.prefer_ipv6) {
+                (None, false) => reqwest::Client::builder().timeout(timeout).build()?,
+                (nat64, prefer_ipv6) => reqwest::Client::builder()
+                    .timeout(timeout)
+                    .dns_resolver(Arc::new(nat64::Resolver { nat64, prefer_ipv6 }))
+                    .build()?,
+            },
+            url: if cli.geo_full {
+                let separator = if path.contains('?') { '&' } else { '?' };
+                format!("{}/{}{}fields={}", GEO_API_HOST, path, separator, GEO_ALL_FIELDS)
+            } else {
+                format!("{}/{}", GEO_API_HOST, path)
+            },
+            protocol: cli.protocol,
+            protocol_order: cli.protocol_order.clone(),
+            baseline_ms: None,
+            insecure: cli.insecure,
+            validate_geo: cli.validate_geo,
+            geo_cache: None,
+            result_cache: None,
+            timings: cli.timings,
+            probe_count: cli.probe_count,
+            test_udp: cli.test_udp,
+            sni: cli.sni.as_deref().map(|host| rawproxy::SniCheck::new(host, cli.sni_target.clone(), cli.insecure)).transpose()?,
+            echo_url: cli.check_headers.then(|| cli.echo_url.clone()),
+            geo_full: cli.geo_full,
+            local_port_range: cli.local_port_range,
+            nat64: cli.nat64_prefix,
+            expect_body: cli.expect_body.clone(),
+            block_signatures: cli.block_signature.clone(),
+            test_ipv6: cli.test_ipv6,
+            geo_timeout: cli.geo_timeout,
+            stages: StageTimeouts::new(timeout, cli.stage_timeout.as_ref()),
+            connect_target: match (cli.connect_only, &cli.connect_target) {
+                (false, _) => None,
+                (true, Some(target)) => Some(target.clone()),
+                // Not the URL's port 80: many HTTP proxies (Squid's default SSL_ports, for one)
+                // refuse CONNECT to anything but 443
+                (true, None) => {
+                    let host = reqwest::Url::parse(GEO_API_HOST)?.host_str().context("Geo API URL has no host")?.to_string();
+                    Some(rawproxy::TunnelTarget { host, port: 443 })
+                }
+            },
+            head_request: cli.head_request,
+            lenient: cli.lenient,
+            accept_status: cli.accept_status.clone(),
+            host_header: cli.host_header.clone(),
+            capture_headers: cli.capture_headers.as_ref().map(|names| names.iter().map(|name| name.to_ascii_lowercase()).collect()),
+            capture_all_headers: cli.capture_all_headers,
+            max_runtime_per_host: cli.max_runtime_per_host,
+        })
+    }
+
+    /// Identifies the settings that decide a test's outcome, so `--result-cache` never
+    /// answers for a run that tests differently. The hash is only stable within one build
+    /// of the scanner; a new build just starts with cache misses.
+    fn fingerprint(&self) -> String {
+        let protocols = (self.protocol, &self.protocol_order, self.probe_count, self.nat64, self.timings);
+        let request = (&self.url, self.head_request, &self.connect_target, &self.host_header);
+        let limits = (self.timeout, self.stages, self.geo_timeout, self.max_runtime_per_host);
+        let verdict = (&self.expect_body, &self.block_signatures, &self.accept_status, self.lenient, self.validate_geo, self.insecure);
+        let checks = (self.test_udp, self.test_ipv6, &self.sni, &self.echo_url, &self.capture_headers, self.capture_all_headers);
+        let mut hasher = std::hash::DefaultHasher::new();
+        format!("{:?}", (protocols, request, limits, verdict, checks)).hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Keeps the headers --capture-headers asks for, names lowercased.
+    fn captured_headers(&self, headers: Vec<(String, String)>) -> Vec<(String, String)> {
+        headers
+            .into_iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .filter(|(name, _)| self.capture_all_headers || self.capture_headers.as_ref().is_some_and(|names| names.contains(name)))
+            .collect()
+    }
 }
 
 // --- Command-Line Interface Definition ---
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[command(flatten)]
     source: Source,
 
-    /// The port to scan or test for
-    #[arg(short, long, default_value_t = 7890)]
-    port: u16,
+    /// The ports to scan or test for, in Nmap syntax (e.g. `8080,3128`, `8000-8100,T:1080`).
+    /// Can be repeated; the lists are merged
+    #[arg(short, long = "ports", visible_alias = "port", value_name = "PORTS", default_value = "7890", action = clap::ArgAction::Append)]
+    port_spec: Vec<ports::PortSpec>,
+
+    /// Read the port list from a file instead, one spec per line
+    #[arg(long, value_name = "PATH", conflicts_with = "port_spec")]
+    ports_from_file: Option<PathBuf>,
 
-    /// Initial connection timeout for port scanning in milliseconds
-    #[arg(long, default_value_t = 200)]
-    scan_timeout: u64,
+    /// The resolved --ports / --ports-from-file list
+    #[arg(skip)]
+    ports: Vec<u16>,
 
-    /// Timeout for the proxy test in seconds
-    #[arg(long, default_value_t = 10)]
-    test_timeout: u64,
+    /// Make the scan-phase connection attempts through this SOCKS5 proxy
+    /// (`socks5://[user:pass@]host:port`), mapping what it can reach
+    #[arg(long, value_name = "PROXY_URL")]
+    scan_via: Option<rawproxy::ScanVia>,
+
+    /// After each successful scan connect, read up to N bytes the service sends first
+    /// (default 256) and keep them as the address's banner
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "256", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    grab_banner: Option<usize>,
+
+    /// Probe a sample of each /24 first (every 16th host) and only scan the blocks with at
+    /// least MIN_OPEN open ports in that sample in full (default 1)
+    #[arg(long, value_name = "MIN_OPEN", num_args = 0..=1, default_missing_value = "1", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    adaptive_sweep: Option<usize>,
+
+    /// Randomize the order of (host, port) probes so multi-port scans don't sweep one port at a time
+    #[arg(long)]
+    shuffle_ports: bool,
+
+    /// Seed every randomized choice (--shuffle-ports, --shuffle-tests) so runs can be reproduced
+    #[arg(long, value_name = "SEED")]
+    seed: Option<u64>,
+
+    /// The proxy protocol to test candidates with
+    #[arg(long, value_enum, default_value_t = Protocol::Http)]
+    protocol: Protocol,
+
+    /// Auto-detect the protocol by trying these in order (e.g. `socks5,http`), keeping
+    /// the first that works
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "PROTOCOLS", conflicts_with = "protocol")]
+    protocol_order: Option<Vec<Protocol>>,
+
+    /// Initial connection timeout for port scanning, e.g. `200ms` or `1s` (bare numbers are milliseconds)
+    #[arg(long, default_value = "200ms", value_parser = parse_millis_or_duration, value_name = "DURATION")]
+    scan_timeout: Duration,
+
+    /// Number of worker threads for port scanning (defaults to the CPU count).
+    /// Scanning is IO-bound, so values well above the core count help on slow
+    /// networks; each thread holds an open socket, so stay below `ulimit -n`.
+    #[arg(long, value_name = "N")]
+    scan_threads: Option<usize>,
+
+    /// Slow the scan down while more than this percentage of probes end in connection
+    /// errors (resets, unreachable networks), speeding back up once they subside
+    #[arg(long, value_name = "PERCENT", num_args = 0..=1, default_missing_value = "20", value_parser = clap::value_parser!(u32).range(1..=100))]
+    auto_throttle: Option<u32>,
+
+    /// Open addresses that may wait between the scan and the tests. A full queue pauses
+    /// the scan until tests catch up
+    #[arg(long, default_value_t = 200, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=tokio::sync::Semaphore::MAX_PERMITS as u64))]
+    queue_size: usize,
+
+    /// Never pause the scan for the tests: every open address is buffered until tested.
+    /// Memory grows with the number of open ports found (a few hundred bytes each)
+    #[arg(long, conflicts_with = "queue_size")]
+    unbounded_queue: bool,
+
+    /// Confirm you are authorized to scan a public (non-private) subnet
+    #[arg(long)]
+    i_understand: bool,
+
+    /// Also scan loopback, multicast, broadcast and link-local addresses inside --subnet ranges
+    /// (addresses given with --ip are always scanned)
+    #[arg(long)]
+    include_special: bool,
+
+    /// Only scan globally routable addresses (skips private IPv4 and unique-local IPv6)
+    #[arg(long, conflicts_with = "include_special")]
+    global_only: bool,
+
+    /// Only use IPv4 addresses from --target and --input
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Only use IPv6 addresses from --target and --input
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Timeout for the proxy test, e.g. `10s` or `1m` (bare numbers are seconds)
+    #[arg(long, default_value = "10s", value_parser = parse_secs_or_duration, value_name = "DURATION")]
+    test_timeout: Duration,
+
+    /// Give test stages their own budgets, e.g. `connect=2s,geo=5s` (stages: connect, geo,
+    /// ipv6, udp, sni, headers; unlisted ones get --test-timeout)
+    #[arg(long, value_name = "STAGE=DURATION,...")]
+    stage_timeout: Option<StageOverrides>,
+
+    /// Hard cap on the time one address may spend in testing, all stages, protocols and
+    /// --second-pass retries included, e.g. `30s`
+    #[arg(long, value_parser = parse_secs_or_duration, value_name = "DURATION")]
+    max_runtime_per_host: Option<Duration>,
+
+    /// Deadline for the geo response body once the proxy has answered, e.g. `3s`; proxies
+    /// whose geo data stalls still count as working with an unknown location
+    #[arg(long, value_parser = parse_secs_or_duration, value_name = "DURATION")]
+    geo_timeout: Option<Duration>,
+
+    /// Path requested on the geo API host during the proxy test
+    #[arg(long, default_value = "/json", value_name = "PATH")]
+    proxy_test_path: String,
+
+    /// Only check that the proxy opens a tunnel (HTTP CONNECT or SOCKS5 CONNECT) to the test
+    /// host on port 443; no request is sent, so there is no geo data
+    #[arg(long, conflicts_with_all = ["expect_body", "geo_full", "test_ipv6"])]
+    connect_only: bool,
+
+    /// Where the --connect-only tunnel goes instead of the test host on 443
+    #[arg(long, value_name = "HOST:PORT", requires = "connect_only")]
+    connect_target: Option<rawproxy::TunnelTarget>,
+
+    /// Send HEAD instead of GET and judge the proxy on the status alone; no body is
+    /// downloaded, so there is no geo data
+    #[arg(long, conflicts_with_all = ["expect_body", "geo_full", "test_ipv6", "connect_only"])]
+    head_request: bool,
+
+    /// Count any HTTP response through the proxy as working, even errors or a failed geo
+    /// lookup (the status code is recorded)
+    #[arg(long)]
+    lenient: bool,
+
+    /// HTTP statuses that count as working, e.g. `200-299,301,302` (default 2xx). The status
+    /// alone decides: proxies whose answer carries no geo data are kept with an unknown location
+    #[arg(long, value_name = "CODES", conflicts_with = "lenient")]
+    accept_status: Option<StatusRanges>,
+
+    /// Send this `Host` header with the test request instead of the test URL's host, for
+    /// endpoints that virtual-host on a name while the URL uses an IP
+    #[arg(long, value_name = "HOST", conflicts_with = "connect_only")]
+    host_header: Option<String>,
+
+    /// Only count a proxy as working if the test response body contains this text
+    #[arg(long, value_name = "SUBSTRING", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    expect_body: Option<String>,
+
+    /// Mark a proxy as blocked, not working, if the response body contains this text (a
+    /// CAPTCHA or block page served with 200). Repeatable
+    #[arg(long, value_name = "SUBSTRING", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    block_signature: Vec<String>,
+
+    /// Accept invalid TLS certificates during the proxy test (dangerous)
+    #[arg(long)]
+    insecure: bool,
+
+    /// Test the ports of each IP one at a time (different IPs still run concurrently)
+    #[arg(long)]
+    serial_per_ip: bool,
+
+    /// Maximum number of proxy tests running at once (defaults to a safe share of the open-file limit)
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_concurrent: Option<usize>,
+
+    /// Start tests in random order, shuffling within windows of N incoming addresses
+    #[arg(long, value_name = "WINDOW", num_args = 0..=1, default_missing_value = "256", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    shuffle_tests: Option<usize>,
+
+    /// Once every test has finished, retest the addresses that failed one more time
+    #[arg(long)]
+    second_pass: bool,
+
+    /// Record a connect / time-to-first-byte / total breakdown per proxy and show it in the table
+    #[arg(long)]
+    timings: bool,
+
+    /// Make proxy test connections from local ports in this range, e.g. `40000-40999`
+    /// (for strict egress firewalls; test URL must be plain HTTP)
+    #[arg(long, value_name = "START-END")]
+    local_port_range: Option<rawproxy::PortRange>,
+
+    /// Reach IPv4 proxies and endpoints through this NAT64 prefix, e.g. `64:ff9b::/96`
+    /// (for IPv6-only hosts without DNS64)
+    #[arg(long, value_name = "PREFIX")]
+    nat64_prefix: Option<nat64::Nat64Prefix>,
+
+    /// Try IPv6 addresses first when resolving endpoints contacted directly (--baseline,
+    /// --validate-geo)
+    #[arg(long)]
+    prefer_ipv6: bool,
+
+    /// Also fetch an IPv6-only endpoint through each working proxy and record whether it can reach IPv6
+    #[arg(long)]
+    test_ipv6: bool,
+
+    /// Also check that SOCKS5 proxies relay UDP (UDP ASSOCIATE plus a DNS round-trip)
+    #[arg(long)]
+    test_udp: bool,
+
+    /// Also check that a TLS handshake naming this host (the SNI) succeeds through the proxy
+    #[arg(long, value_name = "HOSTNAME")]
+    sni: Option<String>,
+
+    /// Where the --sni tunnel goes, e.g. an IP to front through (defaults to the --sni host on 443)
+    #[arg(long, value_name = "HOST:PORT", requires = "sni")]
+    sni_target: Option<rawproxy::TunnelTarget>,
+
+    /// Also send a PUT with known custom headers through each working proxy to --echo-url and
+    /// record whether the method and headers arrive unchanged
+    #[arg(long)]
+    check_headers: bool,
+
+    /// Endpoint for --check-headers; it must echo the request method and headers in its body
+    #[arg(long, default_value = "http://httpbin.org/anything", value_name = "URL", requires = "check_headers")]
+    echo_url: String,
+
+    /// Treat --input as gzip-compressed (implied by a .gz extension)
+    #[arg(long, requires = "input")]
+    gzip: bool,
+
+    /// Stream --input on the async runtime instead of a blocking thread. Rows must fit on
+    /// one line; gzip input is still read on a blocking thread
+    #[arg(long, requires = "input")]
+    async_input: bool,
+
+    /// Abort on the first malformed --input row instead of skipping it
+    #[arg(long, requires = "input")]
+    strict_input: bool,
+
+    /// Exit with an error as soon as any address from --input fails its test
+    #[arg(long, requires = "input", conflicts_with = "second_pass")]
+    strict: bool,
 
     /// Print detailed real-time logs.
     #[arg(long, short)]
     verbose: bool,
 
-    /// Save the final results to a specified CSV file
+    /// Keep ANSI colors even when output is not a terminal or NO_COLOR is set
+    #[arg(long)]
+    force_color: bool,
+
+    /// Emit newline-delimited JSON progress events on stderr instead of the progress bar
+    #[arg(long)]
+    progress_json: bool,
+
+    /// Save the final results to a specified file, or `-` for stdout; repeatable. The format is
+    /// --format if given, else inferred from the extension (.csv, .json, .yaml, .txt, .pac), else CSV
     #[arg(long, short, value_name = "FILE_PATH")]
-    output: Option<PathBuf>,
+    output: Vec<PathBuf>,
+
+    /// Format of the results (defaults to a table on screen; for --output, by file extension)
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Border style of the results table
+    #[arg(long, value_enum, default_value_t = TableStyle::Utf8)]
+    table_style: TableStyle,
+
+    #[command(flatten)]
+    ranking: Ranking,
+
+    /// Print one sub-table per containing network of this prefix length (e.g. `24`)
+    #[arg(long, value_name = "PREFIX_LEN", value_parser = clap::value_parser!(u8).range(0..=128))]
+    group_by: Option<u8>,
+
+    /// Only write these CSV columns, in this order (e.g. `ip,port,latency,country`)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    csv_fields: Option<Vec<output::CsvField>>,
+
+    /// Also copy the working proxies to the clipboard, one `scheme://ip:port` per line
+    #[arg(long, conflicts_with = "no_test")]
+    clipboard: bool,
+
+    /// After showing the results table, ask which ranks to save to --output
+    #[arg(long, requires = "output", conflicts_with_all = ["group_by", "no_test"])]
+    interactive: bool,
+
+    /// Append to the --output CSV file instead of overwriting it
+    #[arg(long, requires = "output")]
+    append: bool,
+
+    /// Skip proxy testing and stream every open address as the scan finds it
+    /// (`--format line`, `csv` or `json`; line by default)
+    #[arg(long, alias = "scan-only", conflicts_with_all = ["baseline", "append", "gzip_output"])]
+    no_test: bool,
+
+    /// With --no-test, the line format to emit open addresses in, for piping into a
+    /// separate validator (`ndjson` is the same as `--format json`)
+    #[arg(long, value_enum, value_name = "FORMAT", requires = "no_test", conflicts_with = "format")]
+    emit_open: Option<EmitFormat>,
+
+    /// Gzip-compress the --output file (implied by a .gz extension)
+    #[arg(long, requires = "output")]
+    gzip_output: bool,
+
+    /// Measure direct latency to the test endpoint and report each proxy's overhead
+    #[arg(long)]
+    baseline: bool,
+
+    /// Skip addresses listed in this file (plain list or CSV, IP or IP:PORT per line)
+    #[arg(long, value_name = "FILE_PATH")]
+    skip_file: Option<PathBuf>,
+
+    /// Write a JSON sidecar describing the run (version, arguments, counts, per-country totals)
+    #[arg(long, value_name = "FILE_PATH")]
+    summary: Option<PathBuf>,
+
+    /// Save the working proxies found so far this often, e.g. `5m`, to each --output file
+    /// (or a temp CSV when results only go to the screen), so a crash loses little
+    #[arg(long, value_parser = parse_secs_or_duration, value_name = "DURATION", conflicts_with_all = ["append", "no_test"])]
+    autosave_interval: Option<Duration>,
+
+    /// Write the addresses whose test failed, with attempts and last error, to this CSV file
+    #[arg(long, value_name = "FILE_PATH")]
+    fail_output: Option<PathBuf>,
+
+    /// Compare the working proxies against a previous results CSV and report what changed
+    #[arg(long, value_name = "PREV_RESULTS")]
+    diff: Option<PathBuf>,
+
+    /// Also write the --diff comparison as CSV (Change, IP Address, Port)
+    #[arg(long, value_name = "FILE_PATH", requires = "diff")]
+    diff_output: Option<PathBuf>,
+
+    /// Test everything, but only print and save proxies missing from this master results CSV
+    #[arg(long, value_name = "MASTER_CSV")]
+    new_only: Option<PathBuf>,
+
+    /// Stop once this many working proxies have been found
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Hold only the N fastest working proxies, dropping slower ones as better ones arrive,
+    /// so memory stays bounded on huge runs
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    top: Option<usize>,
+
+    /// Cross-check each proxy's country against a second geo provider (left blank when that
+    /// provider has no answer)
+    #[arg(long)]
+    validate_geo: bool,
+
+    /// Persist the --validate-geo second-opinion lookups in this JSON file between runs (the
+    /// main geo lookup is the proxy test itself, so it is never cached)
+    #[arg(long, value_name = "FILE_PATH", requires = "validate_geo")]
+    geo_cache: Option<PathBuf>,
+
+    /// Request every field the geo API offers and include the full response in JSON output
+    #[arg(long)]
+    geo_full: bool,
+
+    /// Keep these response headers from the test request in JSON output
+    /// (default `server,via,x-cache`)
+    #[arg(long, value_name = "NAMES", value_delimiter = ',', num_args = 0.., default_missing_value = "server,via,x-cache", conflicts_with = "connect_only")]
+    capture_headers: Option<Vec<String>>,
+
+    /// Keep every response header from the test request in JSON output
+    #[arg(long, conflicts_with_all = ["capture_headers", "connect_only"])]
+    capture_all_headers: bool,
+
+    /// Reuse and record whole test outcomes per IP:port and test settings in this file, skipping recent retests
+    #[arg(long, value_name = "FILE_PATH")]
+    result_cache: Option<PathBuf>,
+
+    /// How long --result-cache entries stay valid, e.g. `30m` or `6h` (bare numbers are seconds)
+    #[arg(long, default_value = "1h", value_parser = parse_secs_or_duration, value_name = "DURATION", requires = "result_cache")]
+    cache_ttl: Duration,
+
+    /// How long cached geo lookups stay valid, e.g. `12h` or `7d` (bare numbers are seconds)
+    #[arg(long, default_value = "7d", value_parser = parse_secs_or_duration, value_name = "DURATION")]
+    geo_cache_ttl: Duration,
+
+    #[command(flatten)]
+    filters: ResultFilters,
+
+    /// Requests to send through each working proxy; with more than one, the reported
+    /// latency is their mean and the jitter is their standard deviation
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+    probe_count: u32,
+
+    /// Drop proxies whose latency varies by more than this many milliseconds (needs --probe-count of 2 or more)
+    #[arg(long, value_name = "MS", requires = "probe_count")]
+    max_jitter: Option<u128>,
+
+    /// Keep at most N working proxies from each country
+    #[arg(long, value_name = "N")]
+    per_country_limit: Option<usize>,
+
+    /// Keep at most N working proxies from each ASN (network operator)
+    #[arg(long, value_name = "N")]
+    per_asn_limit: Option<usize>,
+
+    /// Keep only the fastest proxy for each observed exit IP
+    #[arg(long)]
+    collapse_exit_ip: bool,
+}
+
+/// Accepts a humantime duration (`200ms`, `10s`, `1m`) or, for older scripts, a bare
+/// number in the flag's historical unit.
+fn parse_duration_or(value: &str, bare_unit: fn(u64) -> Duration) -> Result<Duration, String> {
+    match value.trim().parse::<u64>() {
+        Ok(number) => Ok(bare_unit(number)),
+        Err(_) => humantime::parse_duration(value.trim()).map_err(|e| e.to_string()),
+    }
+}
+
+fn parse_millis_or_duration(value: &str) -> Result<Duration, String> {
+    parse_duration_or(value, Duration::from_millis)
+}
+
+fn parse_secs_or_duration(value: &str) -> Result<Duration, String> {
+    parse_duration_or(value, Duration::from_secs)
 }
 
+/// The parts of a proxy test that `--stage-timeout` can budget separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Opening the TCP connection (or `--connect-only` tunnel) to the proxy
+    Connect,
+    /// The test request through the proxy, including each `--probe-count` repeat
+    Geo,
+    Ipv6,
+    Udp,
+    /// The `--sni` tunnel and TLS handshake
+    Sni,
+    /// The `--check-headers` round-trip to the echo endpoint
+    Headers,
+}
+
+impl Stage {
+    const ALL: [Stage; 6] = [Stage::Connect, Stage::Geo, Stage::Ipv6, Stage::Udp, Stage::Sni, Stage::Headers];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Connect => "connect",
+            Stage::Geo => "geo",
+            Stage::Ipv6 => "ipv6",
+            Stage::Udp => "udp",
+            Stage::Sni => "sni",
+            Stage::Headers => "headers",
+        }
+    }
+}
+
+/// `--stage-timeout connect=2s,geo=5s`: per-stage overrides of `--test-timeout`.
+#[derive(Debug, Clone, Default)]
+struct StageOverrides(Vec<(Stage, Duration)>);
+
+impl std::str::FromStr for StageOverrides {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut overrides = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, value) = entry.split_once('=').ok_or_else(|| format!("expected STAGE=DURATION, got {:?}", entry))?;
+            let stage = Stage::ALL.into_iter().find(|stage| stage.name() == name.trim()).ok_or_else(|| {
+                let names: Vec<&str> = Stage::ALL.iter().map(|stage| stage.name()).collect();
+                format!("unknown stage {:?} (expected one of {})", name.trim(), names.join(", "))
+            })?;
+            overrides.push((stage, parse_secs_or_duration(value).map_err(|e| format!("{}: {}", stage.name(), e))?));
+        }
+        Ok(Self(overrides))
+    }
+}
+
+/// `--accept-status 200-299,301`: the HTTP statuses a proxy test accepts.
+#[derive(Debug, Clone)]
+struct StatusRanges(Vec<std::ops::RangeInclusive<u16>>);
+
+impl std::str::FromStr for StatusRanges {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parse = |code: &str| match code.trim().parse::<u16>() {
+            Ok(code) if (100..=599).contains(&code) => Ok(code),
+            _ => Err(format!("invalid HTTP status {:?}", code.trim())),
+        };
+        let mut ranges = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let range = match entry.split_once('-') {
+                Some((start, end)) => parse(start)?..=parse(end)?,
+                None => parse(entry)?..=parse(entry)?,
+            };
+            if range.is_empty() {
+                return Err(format!("{:?} ends before it starts", entry));
+            }
+            ranges.push(range);
+        }
+        if ranges.is_empty() {
+            return Err("expected at least one status code".to_string());
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl StatusRanges {
+    fn contains(&self, status: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&status))
+    }
+}
+
+/// The time each stage may take, `--test-timeout` unless overridden.
+#[derive(Debug, Clone, Copy)]
+struct StageTimeouts {
+    connect: Duration,
+    geo: Duration,
+    ipv6: Duration,
+    udp: Duration,
+    sni: Duration,
+    headers: Duration,
+}
+
+impl StageTimeouts {
+    fn new(default: Duration, overrides: Option<&StageOverrides>) -> Self {
+        let mut stages = Self { connect: default, geo: default, ipv6: default, udp: default, sni: default, headers: default };
+        for &(stage, limit) in overrides.map_or(&[][..], |overrides| &overrides.0) {
+            *stages.get_mut(stage) = limit;
+        }
+        stages
+    }
+
+    fn get(&self, stage: Stage) -> Duration {
+        match stage {
+            Stage::Connect => self.connect,
+            Stage::Geo => self.geo,
+            Stage::Ipv6 => self.ipv6,
+            Stage::Udp => self.udp,
+            Stage::Sni => self.sni,
+            Stage::Headers => self.headers,
+        }
+    }
+
+    fn get_mut(&mut self, stage: Stage) -> &mut Duration {
+        match stage {
+            Stage::Connect => &mut self.connect,
+            Stage::Geo => &mut self.geo,
+            Stage::Ipv6 => &mut self.ipv6,
+            Stage::Udp => &mut self.udp,
+            Stage::Sni => &mut self.sni,
+            Stage::Headers => &mut self.headers,
+        }
+    }
+}
+
+impl Cli {
+    /// Applies --ipv4-only / --ipv6-only to an address.
+    fn allows_family(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => !self.ipv6_only,
+            IpAddr::V6(_) => !self.ipv4_only,
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Command {
+    /// Measure scan throughput across thread counts and timeouts
+    Benchmark(benchmark::BenchmarkArgs),
+    /// Re-filter, re-rank and re-format a saved results file without scanning again
+    Reformat(Box<reformat::ReformatArgs>),
+}
+
+/// How working proxies are ordered; shared by scans and `reformat`.
+#[derive(Debug, Clone, clap::Args)]
+struct Ranking {
+    /// How to order working proxies (and so the table's Rank column)
+    #[arg(long, value_enum, default_value_t = RankBy::Latency)]
+    rank_by: RankBy,
+
+    /// --rank-by score: weight of each millisecond of response time
+    #[arg(long, default_value_t = 1.0, value_name = "WEIGHT")]
+    weight_latency: f64,
+
+    /// --rank-by score: weight of each millisecond of jitter (needs --probe-count)
+    #[arg(long, default_value_t = 2.0, value_name = "WEIGHT")]
+    weight_jitter: f64,
+
+    /// --rank-by score: penalty, in milliseconds, for each retry a proxy needed to pass
+    #[arg(long, default_value_t = 500.0, value_name = "MS")]
+    weight_retry: f64,
+}
+
+/// Filters on working proxies that need no retest; shared by scans and `reformat`.
+#[derive(Debug, Clone, clap::Args)]
+struct ResultFilters {
+    /// Drop proxies that answer faster than this many milliseconds (likely intercepted locally)
+    #[arg(long, value_name = "MS")]
+    min_latency: Option<u128>,
+
+    /// Drop proxies slower than this many milliseconds
+    #[arg(long, value_name = "MS")]
+    max_latency: Option<u128>,
+
+    /// Only keep proxies whose network looks like this type (keyword/ASN heuristic)
+    #[arg(long, value_enum, value_name = "TYPE")]
+    only: Option<ConnectionType>,
+
+    /// Reference point for --max-distance, as `LAT,LON` in decimal degrees
+    #[arg(long, value_name = "LAT,LON", requires = "max_distance", allow_hyphen_values = true)]
+    near: Option<geo::GeoPoint>,
+
+    /// Drop proxies located more than this many kilometres from --near (or with no coordinates)
+    #[arg(long, value_name = "KM", requires = "near")]
+    max_distance: Option<f64>,
+
+    /// Drop proxies announced by these autonomous systems (e.g. `AS15169,16509`)
+    #[arg(long, value_delimiter = ',', value_name = "ASNS")]
+    deny_asn: Vec<String>,
+
+    /// Drop proxies whose ISP name contains this text (case-insensitive); repeatable
+    #[arg(long, value_name = "SUBSTRING", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    deny_isp: Vec<String>,
+}
+
+impl ResultFilters {
+    /// Explains why a result's network is on --deny-asn / --deny-isp, if it is.
+    fn network_rejection(&self, result: &ProxyResult) -> Option<String> {
+        // ip-api reports "AS15169 Google LLC"; accept the number with or without its prefix
+        let as_number = result.asn.as_deref().and_then(|asn| asn.split_whitespace().next()).unwrap_or("");
+        if let Some(denied) = self.deny_asn.iter().find(|denied| {
+            let denied = denied.trim();
+            let denied = denied.strip_prefix("AS").or_else(|| denied.strip_prefix("as")).unwrap_or(denied);
+            as_number.strip_prefix("AS").is_some_and(|number| number == denied)
+        }) {
+            return Some(format!("announced by denied {}", denied.trim()));
+        }
+        let isp = result.isp.as_deref().unwrap_or("").to_lowercase();
+        self.deny_isp
+            .iter()
+            .find(|denied| isp.contains(&denied.to_lowercase()))
+            .map(|denied| format!("ISP matches denied {:?}", denied))
+    }
+
+    /// Explains why a result lies outside --near / --max-distance, if it does.
+    fn distance_rejection(&self, result: &ProxyResult) -> Option<String> {
+        let (near, max_km) = self.near.zip(self.max_distance)?;
+        let Some((lat, lon)) = result.lat.zip(result.lon) else {
+            return Some("has no coordinates to check --max-distance against".to_string());
+        };
+        let km = near.distance_km(geo::GeoPoint { lat, lon });
+        (km > max_km).then(|| format!("{:.0} km away (over {} km)", km, max_km))
+    }
+}
+
+/// Only `--ip` combines with another source (`--subnet` or `--subnet-file`).
 #[derive(Debug, Clone, clap::Args)]
-#[group(required = true, multiple = false)]
+#[group(required = true, multiple = true)]
 struct Source {
     /// The subnet to scan in CIDR notation (e.g., 192.168.1.0/24)
-    #[arg(long)]
+    #[arg(long, conflicts_with = "subnet_file")]
     subnet: Option<String>,
 
+    /// Scan every CIDR block listed in this file, one per line (`#` starts a comment)
+    #[arg(long, value_name = "FILE_PATH")]
+    subnet_file: Option<PathBuf>,
+
+    /// Also scan this individual address; repeatable
+    #[arg(long, value_name = "ADDR")]
+    ip: Vec<IpAddr>,
+
     /// Read IP addresses from a CSV file to test (skips scanning)
-    #[arg(long, short, value_name = "FILE_PATH")]
+    #[arg(long, short, value_name = "FILE_PATH", conflicts_with_all = ["subnet", "subnet_file", "ip", "target"])]
     input: Option<PathBuf>,
+
+    /// Resolve a hostname and test every address it points to (skips scanning)
+    #[arg(long, value_name = "HOSTNAME", conflicts_with_all = ["subnet", "subnet_file", "ip"])]
+    target: Option<String>,
 }
 
 // --- Main Application Logic ---
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    cli.ports = match &cli.ports_from_file {
+        Some(path) => ports::PortSpec::load(path)?.ports().to_vec(),
+        None => ports::PortSpec::merge(&cli.port_spec).ports().to_vec(),
+    };
+    let interactive = configure_terminal(&cli);
+    match &cli.command {
+        Some(Command::Benchmark(args)) => return benchmark::run(args),
+        Some(Command::Reformat(args)) => return reformat::run(args),
+        None => {}
+    }
+
+    if cli.interactive && (cli.output.iter().any(|path| is_stdout(path)) || !io::stdin().is_terminal()) {
+        anyhow::bail!("--interactive needs a terminal on stdin and --output files (not `-`)");
+    }
+    if cli.insecure {
+        eprintln!("[{}] --insecure is set: TLS certificates will NOT be verified during proxy tests.", "WARN".yellow().bold());
+    }
+    let protocols = cli.protocol_order.as_deref().unwrap_or(std::slice::from_ref(&cli.protocol));
+    if cli.test_udp && !protocols.contains(&Protocol::Socks5) {
+        anyhow::bail!("--test-udp only applies to SOCKS5 proxies; add --protocol socks5");
+    }
+
+    // --- Guard Against Unintended Public-Internet Scans ---
+    let mut networks = match (&cli.source.subnet, &cli.source.subnet_file) {
+        (Some(subnet), _) => vec![subnet.parse::<IpNet>().with_context(|| format!("Invalid subnet: {}", subnet))?],
+        (_, Some(path)) => producer::load_subnet_file(path, cli.gzip)?,
+        _ => Vec::new(),
+    };
+    networks.extend(cli.source.ip.iter().map(|&ip| IpNet::from(ip)));
+    for network in &networks {
+        confirm_public_scan(network, cli.i_understand)?;
+    }
+
+    // --- Measure Direct Latency for Comparison ---
+    let mut test_config = TestConfig::from_cli(&cli)?;
+    if let Some(path) = &cli.geo_cache {
+        let cache = GeoCache::load(path, cli.geo_cache_ttl)?;
+        test_config.geo_cache = Some(Arc::new(Mutex::new(cache)));
+    }
+    if let Some(path) = &cli.result_cache {
+        let cache = result_cache::ResultCache::load(path, cli.cache_ttl, test_config.fingerprint())?;
+        test_config.result_cache = Some(Arc::new(Mutex::new(cache)));
+    }
+    if cli.baseline {
+        test_config.baseline_ms = measure_baseline(&test_config).await;
+    }
+    if let Some(ms) = test_config.baseline_ms {
+        print_status(&cli, format!("Baseline direct latency: {} ms", ms));
+    }
+    let test_config = Arc::new(test_config);
 
     // --- Setup UI (Progress Bar for file input, Spinner for subnet scan) ---
-    let progress_bar = setup_ui(&cli)?;
+    let targets = match &cli.source.target {
+        Some(name) => resolve_target(name, &cli)?,
+        None => Vec::new(),
+    };
+    let progress_bar = setup_ui(&cli, (targets.len() * cli.ports.len()) as u64, interactive)?;
+
+    // Load the previous run up front so a bad path fails before the scan, not after it
+    let previous_run = cli.diff.as_deref().map(diff::load_previous).transpose()?;
+    let master_list = cli.new_only.as_deref().map(diff::load_previous).transpose()?;
+
+    // --- Load Known Addresses to Skip ---
+    let skip_list = Arc::new(match &cli.skip_file {
+        Some(path) => SkipList::load(path)?,
+        None => SkipList::default(),
+    });
+
+    let source = match (&cli.source.subnet, &cli.source.subnet_file, &cli.source.input, &cli.source.target) {
+        (Some(subnet), ..) => subnet.clone(),
+        (_, Some(path), ..) | (_, _, Some(path), _) => path.display().to_string(),
+        (.., Some(name)) => name.clone(),
+        _ => String::new(),
+    };
+    let source = std::iter::once(source)
+        .filter(|source| !source.is_empty())
+        .chain(cli.source.ip.iter().map(IpAddr::to_string))
+        .collect::<Vec<_>>()
+        .join(",");
+    log_event(&cli, Event::ScanStarted { source: &source, port: cli.ports[0], ports: &cli.ports });
+    let started_at = humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string();
+
+    // Opened before the scan so a bad --format or path fails fast
+    let address_stream = match cli.no_test {
+        true => {
+            if cli.output.len() > 1 {
+                anyhow::bail!("--no-test streams to a single --output");
+            }
+            let writer: Box<dyn Write> = match cli.output.first() {
+                Some(path) if !is_stdout(path) => {
+                    output::ensure_parent_dir(path)?;
+                    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+                    Box::new(BufWriter::new(file))
+                }
+                _ => Box::new(io::stdout()),
+            };
+            let format = match cli.emit_open {
+                Some(EmitFormat::Ndjson) => OutputFormat::Json,
+                None => cli.format.unwrap_or(OutputFormat::Line),
+            };
+            Some(output::AddressStream::new(writer, format)?)
+        }
+        false => None,
+    };
 
     // --- Setup Communication Channel ---
-    let (tx, mut rx) = mpsc::channel::<SocketAddr>(200);
+    // tokio allocates queue slots as they fill, so the unbounded capacity costs nothing up front
+    let queue_size = if cli.unbounded_queue { tokio::sync::Semaphore::MAX_PERMITS } else { cli.queue_size };
+    let (tx, mut rx) = mpsc::channel::<ProxyTarget>(queue_size);
+
+    // One generator per run; each stage draws its own stream from it so a --seed fixes them all
+    let mut rng = match cli.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
 
     // --- Start Producer Task (Scanner or File Reader) ---
-    let producer_cli = cli.clone();
-    task::spawn_blocking(move || {
-        if let Some(subnet) = producer_cli.source.subnet {
-            scan_and_send(subnet, producer_cli.port, producer_cli.scan_timeout, tx);
-        } else if let Some(path) = producer_cli.source.input {
-            let _ = read_and_send(path, producer_cli.port, tx);
-        }
-    });
+    let stop = Arc::new(AtomicBool::new(false));
+    let producer = Producer {
+        cli: cli.clone(),
+        pb: progress_bar.clone(),
+        skip: skip_list.clone(),
+        stop: stop.clone(),
+        networks,
+        targets,
+        rng: StdRng::seed_from_u64(rng.next_u64()),
+        tx,
+    };
+    let producer_task = if producer.reads_async() {
+        task::spawn(producer.run_async())
+    } else {
+        task::spawn_blocking(move || producer.run())
+    };
+
+    if let Some(stream) = address_stream {
+        return stream_open_addresses(&cli, stream, rx, producer_task, &progress_bar).await;
+    }
 
     // --- Main Concurrency Loop (Consumer) ---
-    let mut test_tasks = JoinSet::new();
-    let mut successful_proxies = Vec::new();
+    let max_concurrent = match cli.max_concurrent {
+        Some(n) => n,
+        None => {
+            let (cap, warning) = scheduler::fd_limited_concurrency();
+            if let Some(warning) = warning {
+                eprintln!("[{}] {}", "WARN".yellow().bold(), warning);
+            }
+            cap
+        }
+    };
+    let mut scheduler = TestScheduler::new(test_config.clone(), cli.serial_per_ip)
+        .with_shuffle_window(cli.shuffle_tests)
+        .with_max_concurrent(max_concurrent)
+        .with_rng(StdRng::seed_from_u64(rng.next_u64()));
+    let mut successful_proxies = KeptResults::new(cli.top);
+    let mut held = HeldCounts::default();
+
+    // --- Keyboard Controls (interactive runs only) ---
+    let (key_listener, keys) = if interactive && !cli.progress_json && io::stdin().is_terminal() {
+        keys::listen().unzip()
+    } else {
+        (None, None)
+    };
+    if keys.is_some() {
+        progress_bar.println(format!("[{}]    Press space to pause/resume testing, q to stop and keep results so far", "KEYS".dimmed().bold()));
+    }
+    // Failed targets kept for --second-pass; `None` once that pass has started
+    let mut retry_queue = cli.second_pass.then(Vec::new);
+    let mut recovered = None;
+    let mut failed: Vec<ProxyTarget> = Vec::new();
+    let mut blocked = 0;
 
+    let scan_meta = ScanMeta {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: std::env::args().collect(),
+        started_at,
+        source: source.clone(),
+        port: cli.ports[0],
+        ports: cli.ports.clone(),
+        scan_timeout_ms: cli.scan_timeout.as_millis() as u64,
+        test_timeout_secs: cli.test_timeout.as_secs(),
+    };
+
+    // --- Periodic Autosave ---
+    let autosave_fallback = std::env::temp_dir().join(format!("subnet_scanner-autosave-{}.csv", std::process::id()));
+    let autosave = cli.autosave_interval.map(|every| tokio::time::interval_at(tokio::time::Instant::now() + every, every));
+    if let Some(every) = cli.autosave_interval {
+        let target = match cli.output.iter().any(|path| !is_stdout(path)) {
+            true => "--output".to_string(),
+            false => autosave_fallback.display().to_string(),
+        };
+        progress_bar.println(format!("[{}]    Saving results every {} to {}", "SAVE".dimmed().bold(), humantime::format_duration(every), target));
+    }
+
+    let mut controls = consumer::Controls { keys, paused_message: None, autosave };
     loop {
-        tokio::select! {
-            Some(addr) = rx.recv() => {
-                log_verbose(&progress_bar, &cli, format!("[{}]   Potential proxy at {}", "FOUND".cyan().bold(), addr));
-                test_tasks.spawn(test_proxy(addr, cli.test_timeout));
-            },
-            Some(res) = test_tasks.join_next(), if !test_tasks.is_empty() => {
-                // Only increment progress bar if it's not a spinner
-                if progress_bar.length().is_some() { progress_bar.inc(1); }
+        match consumer::next_step(&mut rx, &mut scheduler, &mut controls, &progress_bar).await {
+            consumer::Step::Quit => {
+                progress_bar.println(format!("[{}]    Stopping early, keeping {} working proxies", "KEYS".dimmed().bold(), successful_proxies.len()));
+                stop.store(true, Ordering::Relaxed);
+                scheduler.abort_all();
+                break;
+            }
+            consumer::Step::Autosave => {
+                if let Err(e) = autosave_results(&cli, &scan_meta, &autosave_fallback, &successful_proxies.snapshot()) {
+                    progress_bar.println(format!("[{}] Autosave failed: {:#}", "WARN".yellow().bold(), e));
+                }
+            }
+            consumer::Step::Target(target) => {
+                log_verbose(&progress_bar, &cli, format!("[{}]   Potential proxy at {}", "FOUND".cyan().bold(), target.addr));
+                log_event(&cli, Event::ProxyFound { address: target.addr });
+                scheduler.submit(target);
+            }
+            consumer::Step::Finished(res) => {
+                // Spinners count completions too, so the position is already right
+                // when a subnet scan finishes and turns the spinner into a bar
+                progress_bar.inc(1);
 
                 match res {
                     Ok(Ok(result)) => { // Task succeeded, and proxy test succeeded
-                        log_verbose(&progress_bar, &cli, format!("[{}] {} connected in {}ms", "SUCCESS".green().bold(), result.ip_address, result.response_time_ms));
+                        let host_note = cli.host_header.as_deref().map(|host| format!(" (Host: {})", host)).unwrap_or_default();
+                        log_verbose(&progress_bar, &cli, format!("[{}] {} connected in {}ms{}", "SUCCESS".green().bold(), result.ip_address, result.response_time_ms, host_note));
                         log_verbose(&progress_bar, &cli, format!("[{}]      {} located in {}", "GEO".blue().bold(), result.ip_address, result.location));
-                        successful_proxies.push(result);
+                        if result.geo_confident == Some(false) {
+                            log_verbose(&progress_bar, &cli, format!("[{}]      {} geo providers disagree on its country", "GEO".yellow().bold(), result.ip_address));
+                        }
+                        log_event(&cli, Event::ProxyTested {
+                            address: SocketAddr::new(result.ip_address, result.port),
+                            success: true,
+                            response_time_ms: Some(result.response_time_ms),
+                            location: Some(&result.location),
+                            error: None,
+                        });
+
+                        if let Some(reason) = latency_rejection(&cli.filters, cli.max_jitter, &result) {
+                            log_verbose(&progress_bar, &cli, format!("[{}]   {} dropped, {}", "FILTER".magenta().bold(), result.ip_address, reason));
+                            continue;
+                        }
+                        if let Some(reason) = cli.filters.network_rejection(&result) {
+                            log_verbose(&progress_bar, &cli, format!("[{}]   {} dropped, {}", "FILTER".magenta().bold(), result.ip_address, reason));
+                            continue;
+                        }
+                        if let Some(reason) = cli.filters.distance_rejection(&result) {
+                            log_verbose(&progress_bar, &cli, format!("[{}]   {} dropped, {}", "FILTER".magenta().bold(), result.ip_address, reason));
+                            continue;
+                        }
+                        if cli.filters.only.is_some_and(|only| only != result.connection_type) {
+                            log_verbose(&progress_bar, &cli, format!("[{}]   {} dropped, looks like a {:?} network", "FILTER".magenta().bold(), result.ip_address, result.connection_type));
+                            continue;
+                        }
+                        let caps = [(cli.per_country_limit, &held.per_country, country_key(&result)), (cli.per_asn_limit, &held.per_asn, asn_key(&result))];
+                        let full = caps.into_iter().find_map(|(cap, counts, key)| cap.filter(|&cap| counts.get(&key).is_some_and(|&n| n >= cap)).map(|cap| (cap, key)));
+                        if let Some((cap, key)) = full {
+                            log_verbose(&progress_bar, &cli, format!("[{}]    {} dropped, already have {} from {}", "CAP".magenta().bold(), result.ip_address, cap, key));
+                            continue;
+                        }
+                        // Counted only while held, so a result --top evicts gives its slot back
+                        held.add(&result);
+                        if let Some(evicted) = successful_proxies.push(result) {
+                            held.remove(&evicted);
+                        }
+
+                        if cli.limit.is_some_and(|limit| successful_proxies.found() >= limit) {
+                            log_verbose(&progress_bar, &cli, format!("[{}]   Reached --limit of {} proxies, stopping", "LIMIT".magenta().bold(), successful_proxies.found()));
+                            stop.store(true, Ordering::Relaxed);
+                            scheduler.abort_all();
+                            break;
+                        }
                     }
-                    Ok(Err((addr, e))) => { // Task succeeded, but proxy test failed
-                        log_verbose(&progress_bar, &cli, format!("[{}]     {}: {}", "FAIL".red().bold(), addr, e));
+                    Ok(Err(failure)) => { // Task succeeded, but proxy test failed
+                        let (target, e) = *failure;
+                        let is_blocked = e.is::<Blocked>();
+                        let line = match is_blocked {
+                            true => format!("[{}] {}: {}", "BLOCKED".yellow().bold(), target.addr, e),
+                            false => format!("[{}]     {}: {}", "FAIL".red().bold(), target.addr, e),
+                        };
+                        log_verbose(&progress_bar, &cli, line);
+                        log_event(&cli, Event::ProxyTested {
+                            address: target.addr,
+                            success: false,
+                            response_time_ms: None,
+                            location: None,
+                            error: Some(e.to_string()),
+                        });
+                        if cli.strict {
+                            stop.store(true, Ordering::Relaxed);
+                            scheduler.abort_all();
+                            progress_bar.abandon();
+                            anyhow::bail!("--strict: {} failed its test: {}", target.addr, e);
+                        }
+                        // Retrying won't change what the site serves this proxy
+                        if is_blocked {
+                            blocked += 1;
+                            continue;
+                        }
+                        let mut target = target;
+                        target.attempts += 1;
+                        target.last_error = Some(e.to_string());
+                        match retry_queue.as_mut() {
+                            Some(queue) => queue.push(target),
+                            None => failed.push(target),
+                        }
                     }
                     Err(e) => { // Task itself failed to execute
                          log_verbose(&progress_bar, &cli, format!("[{}]   A test task failed: {}", "ERROR".yellow().bold(), e));
                     }
                 }
-            },
-            else => break,
+            }
+            consumer::Step::Idle => {
+                // The producer is done; start whatever is still waiting in the shuffle window
+                if scheduler.flush() {
+                    continue;
+                }
+                // Everything has been tested once; give the failures a second chance
+                // through the same filters now that the network may have settled
+                match retry_queue.take() {
+                    Some(queue) if !queue.is_empty() => {
+                        log_verbose(&progress_bar, &cli, format!("[{}]    Second pass: retesting {} failed addresses", "RETRY".cyan().bold(), queue.len()));
+                        progress_bar.inc_length(queue.len() as u64);
+                        recovered = Some(successful_proxies.found());
+                        for target in queue {
+                            scheduler.submit(target);
+                        }
+                    }
+                    _ => break,
+                }
+            }
         }
     }
 
+    // Closing the channel unblocks a producer stuck on a full queue after an early stop
+    drop(rx);
+    drop(key_listener);
+    if let Err(e) = producer_task.await? {
+        progress_bar.abandon();
+        return Err(e);
+    }
     progress_bar.finish_with_message("All tasks completed!");
+    if let Some(before) = recovered {
+        print_status(&cli, format!("Second pass recovered {} proxies.", successful_proxies.found() - before));
+    }
+    if let Some(top) = cli.top.filter(|&top| successful_proxies.found() > top) {
+        print_status(&cli, format!("Kept the {} fastest of {} working proxies (--top).", top, successful_proxies.found()));
+    }
+    let mut successful_proxies = successful_proxies.into_vec();
+    log_event(&cli, Event::Finished { working: successful_proxies.len() });
+
+    if let Some(cache) = &test_config.geo_cache {
+        let mut cache = cache.lock().unwrap();
+        cache.save()?;
+        print_status(&cli, format!("\nGeo cache: {} hits, {} entries saved.", cache.hits, cache.len()));
+    }
+    if let Some(cache) = &test_config.result_cache {
+        let mut cache = cache.lock().unwrap();
+        cache.save()?;
+        print_status(&cli, format!("\nResult cache: {} addresses reused, {} entries saved.", cache.hits(), cache.len()));
+    }
+
+    if blocked > 0 {
+        print_status(&cli, format!("\n{} proxies reached the test URL but were served a block page.", blocked));
+    }
+
+    let skipped = skip_list.skipped.load(Ordering::Relaxed);
+    if skipped > 0 {
+        print_status(&cli, format!("\nSkipped {} addresses already listed in the skip file.", skipped));
+    }
+
+    if let Some(path) = &cli.fail_output {
+        output::write_atomically(path, |writer| {
+            let mut writer = csv::Writer::from_writer(writer);
+            for target in &failed {
+                writer.serialize(FailedTest::from(target))?;
+            }
+            writer.flush()?;
+            Ok(())
+        })?;
+        print_status(&cli, format!("\nWrote {} failed addresses to {}.", failed.len(), path.display()));
+    }
+
+    if let Some(previous) = &previous_run {
+        let pool_diff = diff::PoolDiff::compute(previous, &successful_proxies);
+        print_status(&cli, format!("\n{}", pool_diff.summary()));
+        if let Some(path) = &cli.diff_output {
+            output::write_atomically(path, |writer| pool_diff.write_csv(writer))?;
+        }
+    }
+
+    if let Some(master) = &master_list {
+        let before = successful_proxies.len();
+        successful_proxies.retain(|result| !master.contains(&SocketAddr::new(result.ip_address, result.port)));
+        print_status(&cli, format!("\n{} of {} working proxies are new (not in the master list).", successful_proxies.len(), before));
+    }
 
     // --- Display and Save Results ---
     if successful_proxies.is_empty() {
-        println!("\nNo working HTTP proxies were found.");
+        print_status(&cli, "\nNo working HTTP proxies were found.");
     } else {
-        println!("\n--- Final Results ---");
-        successful_proxies.sort_by(|a, b| a.response_time_ms.cmp(&b.response_time_ms));
-        display_results(&successful_proxies);
+        successful_proxies.sort_by_key(|result| result.response_time_ms);
+
+        let shared = report_shared_exit_ips(&mut successful_proxies, cli.collapse_exit_ip);
+        // Collapsing relies on latency order, so a composite ranking is applied afterwards
+        if cli.ranking.rank_by == RankBy::Score {
+            successful_proxies.sort_by(|a, b| quality_score(&cli.ranking, a).total_cmp(&quality_score(&cli.ranking, b)));
+        }
+        if shared > 0 && cli.collapse_exit_ip {
+            print_status(&cli, format!("\nCollapsed {} proxies that share an exit IP with a faster one.", shared));
+        } else if shared > 0 {
+            print_status(&cli, format!("\n{} proxies share an exit IP with a faster one (use --collapse-exit-ip to drop them).", shared));
+        }
 
-        if let Some(path) = cli.output {
-            save_to_csv(&path, &successful_proxies)?;
-            println!("\nResults saved to {}", path.display());
+        if cli.per_country_limit.is_some() {
+            let counts: Vec<String> = held.per_country.iter().map(|(country, n)| format!("{}: {}", country, n)).collect();
+            print_status(&cli, format!("\nProxies per country: {}", counts.join(", ")));
+        }
+        if cli.per_asn_limit.is_some() {
+            let counts: Vec<String> = held.per_asn.iter().map(|(asn, n)| format!("{}: {}", asn, n)).collect();
+            print_status(&cli, format!("\nProxies per ASN: {}", counts.join(", ")));
+        }
+        print_status(&cli, format!("\n{}", output::latency_histogram(&successful_proxies)));
+
+        let output_options = OutputOptions {
+            timings: cli.timings,
+            table_style: cli.table_style,
+            group_by: cli.group_by,
+            csv_fields: cli.csv_fields.clone(),
+            scan: scan_meta.clone(),
+        };
+        if cli.output.is_empty() {
+            let format = cli.format.unwrap_or(OutputFormat::Table);
+            if format == OutputFormat::Table {
+                println!("\n--- Final Results ---");
+            }
+            output::sink_for(format, Box::new(io::stdout()), &output_options).write_results(&successful_proxies)?;
+        } else if !cli.output.iter().any(|path| is_stdout(path)) {
+            println!("\n--- Final Results ---");
+            TableSink::new(io::stdout(), &output_options).write_results(&successful_proxies)?;
+            if cli.interactive {
+                successful_proxies = pick_by_rank(successful_proxies)?;
+            }
+        }
+        for path in &cli.output {
+            let format = cli.format.or_else(|| OutputFormat::from_extension(path)).unwrap_or(OutputFormat::Csv);
+            if is_stdout(path) {
+                output::sink_for(format, Box::new(io::stdout()), &output_options).write_results(&successful_proxies)?;
+                continue;
+            }
+            let gzip = producer::is_gzip(path, cli.gzip_output);
+            write_results_to_file(path, format, cli.append, gzip, &output_options, &successful_proxies)?;
+            print_status(&cli, format!("\nResults saved to {}", path.display()));
+        }
+        if cli.clipboard {
+            match output::copy_to_clipboard(&output::proxy_list(&successful_proxies)) {
+                Ok(()) => print_status(&cli, format!("\nCopied {} proxies to the clipboard.", successful_proxies.len())),
+                Err(e) => eprintln!("[{}] Could not copy to the clipboard: {}", "WARN".yellow().bold(), e),
+            }
         }
     }
 
+    // The run finished, so the safety copy has served its purpose
+    if cli.autosave_interval.is_some() {
+        let _ = std::fs::remove_file(&autosave_fallback);
+    }
+
+    if let Some(path) = &cli.summary {
+        let summary = output::RunSummary::new(&scan_meta, &successful_proxies, failed.len(), blocked, skipped);
+        output::write_atomically(path, |writer| {
+            serde_json::to_writer_pretty(&mut *writer, &summary)?;
+            writeln!(writer)?;
+            Ok(())
+        })?;
+    }
+
     Ok(())
 }
 
 // --- Helper and Worker Functions ---
 
-fn setup_ui(cli: &Cli) -> Result<ProgressBar> {
-    if let Some(path) = &cli.source.input {
-        // Use a progress bar for file input
-        let file = std::fs::File::open(path)?;
-        let mut rdr = csv::Reader::from_reader(file);
-        let total_tasks = rdr.records().count() as u64;
-        let pb = ProgressBar::new(total_tasks);
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%)")?.progress_chars("##-"));
-        Ok(pb)
+/// Environment variable that acknowledges authorization in place of --i-understand.
+const AUTHORIZED_ENV: &str = "SUBNET_SCANNER_AUTHORIZED";
+
+/// Ranges that never leave the local network, so scanning them needs no confirmation.
+fn is_private_network(network: &IpNet) -> bool {
+    const PRIVATE_RANGES: [&str; 9] = [
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "fc00::/7",
+        "fe80::/10",
+        "::1/128",
+    ];
+    PRIVATE_RANGES
+        .iter()
+        .filter_map(|range| range.parse::<IpNet>().ok())
+        .any(|range| range.contains(network))
+}
+
+/// Refuses to scan public address space unless the user has explicitly confirmed
+/// they are authorized to (`acknowledged` is `--i-understand`), and leaves a note on
+/// stderr when they have. Every command that scans goes through here.
+fn confirm_public_scan(network: &IpNet, acknowledged: bool) -> Result<()> {
+    if is_private_network(network) {
+        return Ok(());
+    }
+    let env_ack = std::env::var(AUTHORIZED_ENV).is_ok_and(|value| value == "1");
+    if !acknowledged && !env_ack {
+        anyhow::bail!(
+            "{} is public address space. Scanning networks you are not authorized to test may be illegal.\n\
+             Re-run with --i-understand (or {}=1) to confirm you have permission.",
+            network,
+            AUTHORIZED_ENV
+        );
+    }
+    eprintln!("[{}] Scanning public range {}; authorization acknowledged by the operator.", "WARN".yellow().bold(), network);
+    Ok(())
+}
+
+/// Writes the results so far to every --output file, or to `fallback` as CSV when nothing
+/// is saved to disk. Each write replaces the file atomically, so a crash mid-save leaves
+/// the previous snapshot intact.
+fn autosave_results(cli: &Cli, scan: &ScanMeta, fallback: &Path, results: &[ProxyResult]) -> Result<()> {
+    let options = OutputOptions {
+        timings: cli.timings,
+        table_style: cli.table_style,
+        group_by: cli.group_by,
+        csv_fields: cli.csv_fields.clone(),
+        scan: scan.clone(),
+    };
+    let files: Vec<&PathBuf> = cli.output.iter().filter(|path| !is_stdout(path)).collect();
+    if files.is_empty() {
+        return write_results_to_file(fallback, OutputFormat::Csv, false, false, &options, results);
+    }
+    for path in files {
+        let format = cli.format.or_else(|| OutputFormat::from_extension(path)).unwrap_or(OutputFormat::Csv);
+        write_results_to_file(path, format, false, producer::is_gzip(path, cli.gzip_output), &options, results)?;
+    }
+    Ok(())
+}
+
+/// Decides whether we are talking to a person at a terminal and turns ANSI colors
+/// off when not (or when NO_COLOR is set), unless --force-color asks otherwise.
+fn configure_terminal(cli: &Cli) -> bool {
+    let interactive = io::stderr().is_terminal();
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    colored::control::set_override(cli.force_color || (interactive && !no_color));
+    interactive
+}
+
+fn setup_ui(cli: &Cli, target_count: u64, interactive: bool) -> Result<ProgressBar> {
+    let pb = build_progress_bar(cli, target_count)?;
+    if cli.progress_json || !interactive {
+        // The JSON event stream owns stderr; otherwise there is no terminal to animate
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    Ok(pb)
+}
+
+fn build_progress_bar(cli: &Cli, target_count: u64) -> Result<ProgressBar> {
+    if cli.source.input.is_some() {
+        // Use a progress bar for file input; the producer extends it as it reads
+        determinate_bar(0)
+    } else if cli.source.target.is_some() {
+        // The resolved address count is known up front too
+        determinate_bar(target_count)
     } else {
         // Use a spinner for subnet scanning
         let pb = ProgressBar::new_spinner();
@@ -180,102 +1613,742 @@ fn setup_ui(cli: &Cli) -> Result<ProgressBar> {
     }
 }
 
-fn log_verbose(pb: &ProgressBar, cli: &Cli, msg: String) {
-    if cli.verbose {
-        pb.println(msg);
+fn determinate_bar(total: u64) -> Result<ProgressBar> {
+    let pb = ProgressBar::new(total);
+    pb.set_style(bar_style()?);
+    Ok(pb)
+}
+
+/// Style for progress with a known total; subnet scans switch to it once scanning ends.
+fn bar_style() -> Result<ProgressStyle> {
+    Ok(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({percent}%) {msg}")?.progress_chars("##-"))
+}
+
+/// Expands `--target` into every A/AAAA address it resolves to.
+fn resolve_target(name: &str, cli: &Cli) -> Result<Vec<IpAddr>> {
+    let mut ips = dns_lookup::lookup_host(name)
+        .map_err(|e| anyhow::anyhow!("Could not resolve target {}: {}", name, e))?;
+    ips.retain(|ip| cli.allows_family(ip));
+    ips.sort();
+    ips.dedup();
+    if ips.is_empty() {
+        anyhow::bail!("Target {} did not resolve to any usable addresses", name);
     }
+    Ok(ips)
 }
 
-fn scan_and_send(subnet_str: String, port: u16, timeout_ms: u64, tx: mpsc::Sender<SocketAddr>) {
-    if let Ok(network) = subnet_str.parse::<IpNet>() {
-        let hosts_to_scan: Vec<IpAddr> = network.hosts().collect();
-        hosts_to_scan.into_par_iter().for_each(|ip| {
-            let addr = SocketAddr::new(ip, port);
-            let timeout = Duration::from_millis(timeout_ms);
-            if TcpStream::connect_timeout(&addr, timeout).is_ok() {
-                let _ = tx.blocking_send(addr);
-            }
-        });
+fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// `--no-test`: writes addresses as the producer sends them, then reports the count.
+async fn stream_open_addresses(
+    cli: &Cli,
+    mut stream: output::AddressStream<Box<dyn Write>>,
+    mut rx: mpsc::Receiver<ProxyTarget>,
+    producer_task: task::JoinHandle<Result<()>>,
+    progress_bar: &ProgressBar,
+) -> Result<()> {
+    while let Some(target) = rx.recv().await {
+        log_event(cli, Event::ProxyFound { address: target.addr });
+        stream.write(target.addr)?;
+        progress_bar.inc(1);
+    }
+    if let Err(e) = producer_task.await? {
+        progress_bar.abandon();
+        return Err(e);
     }
+    progress_bar.finish_and_clear();
+    print_status(cli, format!("{} open addresses (untested).", stream.written()));
+    log_event(cli, Event::Finished { working: 0 });
+    Ok(())
 }
 
-fn read_and_send(path: PathBuf, default_port: u16, tx: mpsc::Sender<SocketAddr>) -> Result<()> {
-    let file = std::fs::File::open(path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let record: ProxyInputRecord = result?;
-        // Handle both IP:PORT and just IP formats from input CSV
-        if let Ok(addr) = record.ip_address.parse::<SocketAddr>() {
-            let _ = tx.blocking_send(addr);
-        } else if let Ok(ip) = record.ip_address.parse::<IpAddr>() {
-            let addr = SocketAddr::new(ip, default_port);
-            let _ = tx.blocking_send(addr);
+/// Prints a human-oriented status line, keeping stdout clean when it carries result data.
+fn print_status(cli: &Cli, msg: impl std::fmt::Display) {
+    let data_on_stdout = match cli.output.is_empty() {
+        false => cli.output.iter().any(|path| is_stdout(path)),
+        true => cli.no_test || cli.format.is_some_and(|format| format != OutputFormat::Table),
+    };
+    if data_on_stdout {
+        // stderr belongs to the event stream when --progress-json is set
+        if !cli.progress_json {
+            eprintln!("{}", msg);
         }
+    } else {
+        println!("{}", msg);
     }
-    Ok(())
 }
 
-async fn test_proxy(addr: SocketAddr, timeout_sec: u64) -> Result<ProxyResult, (SocketAddr, anyhow::Error)> {
-    let test_logic = async {
-        const GEO_API_URL: &str = "http://ip-api.com/json";
-        let timeout = Duration::from_secs(timeout_sec);
-        let proxy_addr_str = format!("http://{}", addr);
-        let proxy = Proxy::all(proxy_addr_str)?;
-        let client = reqwest::Client::builder().proxy(proxy).timeout(timeout).build()?;
+fn log_event(cli: &Cli, event: Event) {
+    if cli.progress_json {
+        events::emit(&event);
+    }
+}
 
+fn log_verbose(pb: &ProgressBar, cli: &Cli, msg: String) {
+    if !cli.verbose || cli.progress_json {
+        return;
+    }
+    // A hidden bar swallows println, so write plain lines instead
+    if pb.is_hidden() {
+        eprintln!("{}", msg);
+    } else {
+        pb.println(msg);
+    }
+}
+
+async fn measure_baseline(config: &TestConfig) -> Option<u128> {
+    let measure = async {
         let start_time = Instant::now();
-        let response = client.get(GEO_API_URL).send().await?;
-        let response_time = start_time.elapsed();
+        config.direct_client.get(&config.url).send().await?.error_for_status()?;
+        Ok::<_, anyhow::Error>(start_time.elapsed().as_millis())
+    };
+
+    match measure.await {
+        Ok(ms) => Some(ms),
+        Err(e) => {
+            eprintln!("[{}] Could not measure baseline latency: {}", "WARN".yellow().bold(), e);
+            None
+        }
+    }
+}
+
+/// reqwest's header map as owned name/value pairs; values that aren't valid text are dropped.
+fn header_pairs(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers.iter().filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string()))).collect()
+}
+
+/// One response read through the proxy under test.
+struct Fetched {
+    /// Time until the response headers arrived
+    ttfb: Duration,
+    /// `None` for --connect-only, which sends no request
+    status: Option<u16>,
+    /// Response headers named by --capture-headers (or all of them); empty when not capturing
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+async fn test_proxy(mut target: ProxyTarget, config: Arc<TestConfig>) -> scheduler::TestOutcome {
+    let (addr, credentials) = (target.addr, &target.credentials);
+    // Where connections to the proxy go; results still report `addr`
+    let dial = config.nat64.map_or(addr, |prefix| prefix.synthesize(addr));
+    let cached = config.result_cache.as_ref().and_then(|cache| cache.lock().unwrap().get(addr));
+    match cached {
+        Some(result_cache::CachedOutcome::Working(result)) => {
+            return Ok(ProxyResult { credentials: credentials.clone(), banner: target.banner.clone(), ..ProxyResult::from(*result) });
+        }
+        Some(result_cache::CachedOutcome::Blocked(signature)) => {
+            return Err(Box::new((target, Blocked { signature, cached: true }.into())));
+        }
+        // A --second-pass retry exists to test again, so it never gets a cached failure back
+        Some(result_cache::CachedOutcome::Failed(error)) if target.attempts == 0 => {
+            return Err(Box::new((target, anyhow::anyhow!("{} (cached)", error))));
+        }
+        Some(result_cache::CachedOutcome::Failed(_)) | None => {}
+    }
+    let test_logic = async |protocol: Protocol| -> Result<ProxyResult> {
+        let proxy_addr_str = format!("{}://{}", protocol.scheme(), dial);
+        let mut proxy = Proxy::all(proxy_addr_str)?;
+        if let Some(credentials) = &credentials {
+            proxy = proxy.basic_auth(&credentials.username, &credentials.password);
+        }
+        // Every request through this proxy (geo, --probe-count, --test-ipv6) goes through this
+        // one client, so later checks reuse the kept-alive connection instead of reconnecting.
+        // Idle connections only need to outlive the test itself.
+        let client = reqwest::Client::builder()
+            .proxy(proxy)
+            .timeout(config.timeout)
+            .connect_timeout(config.stages.connect)
+            .pool_idle_timeout(config.timeout)
+            .pool_max_idle_per_host(1)
+            .tcp_nodelay(true)
+            .danger_accept_invalid_certs(config.insecure)
+            .build()?;
+
+        // reqwest hides its socket, so time a separate TCP connect to the proxy. TLS to
+        // an HTTPS test URL can't be split out and is counted in the TTFB figure.
+        let connect_ms = if config.timings {
+            let connect_start = Instant::now();
+            match config.local_port_range {
+                Some(range) => drop(tokio::time::timeout(config.stages.connect, rawproxy::connect_from_range(dial, range)).await??),
+                None => drop(tokio::time::timeout(config.stages.connect, tokio::net::TcpStream::connect(dial)).await??),
+            }
+            Some(connect_start.elapsed().as_millis())
+        } else {
+            None
+        };
+
+        // One request through the proxy. With --geo-timeout the body gets its own deadline
+        // once headers are in, and `body` is `None` when it stalled (or with --head-request).
+        // --lenient counts any answer as proof the proxy relays traffic
+        let check_status = |fetched: Fetched| match fetched.status {
+            Some(status) if !config.lenient && !config.accept_status.as_ref().map_or((200..300).contains(&status), |accept| accept.contains(status)) => {
+                anyhow::bail!("HTTP status {}", status)
+            }
+            _ => Ok(fetched),
+        };
+        let fetch = async |url: &str| -> Result<Fetched> {
+            if let Some(target) = &config.connect_target {
+                let handshake = rawproxy::connect_handshake(dial, protocol, credentials.as_ref(), target, config.local_port_range);
+                let ttfb = tokio::time::timeout(config.stages.connect, handshake).await??;
+                return Ok(Fetched { ttfb, status: None, headers: Vec::new(), body: None });
+            }
+            // --host-header is meant for the test URL, not follow-ups like --test-ipv6
+            let host_header = config.host_header.as_deref().filter(|_| url == config.url);
+            let start = Instant::now();
+            let fetched = match config.local_port_range {
+                Some(range) => {
+                    let method = if config.head_request { "HEAD" } else { "GET" };
+                    let extra: Vec<(String, String)> = host_header.map(|host| ("Host".to_string(), host.to_string())).into_iter().collect();
+                    let fetch = rawproxy::request(dial, protocol, credentials.as_ref(), method, url, &extra, range, config.geo_timeout);
+                    let response = tokio::time::timeout(config.timeout, fetch).await??;
+                    let body = response.body.filter(|_| !config.head_request);
+                    let headers = config.captured_headers(response.headers);
+                    Fetched { ttfb: response.ttfb, status: Some(response.status), headers, body }
+                }
+                None => {
+                    let with_host = |request: reqwest::RequestBuilder| match host_header {
+                        Some(host) => request.header(reqwest::header::HOST, host),
+                        None => request,
+                    };
+                    if config.head_request {
+                        let response = with_host(client.head(url)).send().await?;
+                        let headers = config.captured_headers(header_pairs(response.headers()));
+                        return check_status(Fetched { ttfb: start.elapsed(), status: Some(response.status().as_u16()), headers, body: None });
+                    }
+                    let response = with_host(client.get(url)).send().await?;
+                    let (ttfb, status) = (start.elapsed(), response.status().as_u16());
+                    // Read before `bytes()` consumes the response
+                    let headers = config.captured_headers(header_pairs(response.headers()));
+                    let body = match config.geo_timeout {
+                        Some(limit) => match tokio::time::timeout(limit, response.bytes()).await {
+                            Ok(body) => Some(body?.to_vec()),
+                            Err(_) => None,
+                        },
+                        None => Some(response.bytes().await?.to_vec()),
+                    };
+                    Fetched { ttfb, status: Some(status), headers, body }
+                }
+            };
+            check_status(fetched)
+        };
+        let staged_fetch = async |stage: Stage, url: &str| -> Result<Fetched> {
+            let limit = config.stages.get(stage);
+            match tokio::time::timeout(limit, fetch(url)).await {
+                Ok(fetched) => fetched,
+                Err(_) => anyhow::bail!("{} stage timed out after {}", stage.name(), humantime::format_duration(limit)),
+            }
+        };
 
-        let geo_info = response.json::<GeoLocationResponse>().await?;
+        let start_time = Instant::now();
+        let Fetched { ttfb: response_time, status: status_code, headers, body } = staged_fetch(Stage::Geo, &config.url).await?;
+        // Checked before parsing so interception pages fail with a clear reason
+        let body_matched = match (&config.expect_body, &body) {
+            (Some(needle), Some(body)) if body.windows(needle.len()).any(|window| window == needle.as_bytes()) => Some(true),
+            (Some(needle), _) => anyhow::bail!("Response body does not contain {:?}", needle),
+            (None, _) => None,
+        };
+        if let Some(body) = &body
+            && let Some(signature) = config.block_signatures.iter().find(|sig| body.windows(sig.len()).any(|window| window == sig.as_bytes()))
+        {
+            return Err(Blocked { signature: signature.clone(), cached: false }.into());
+        }
+        // With --accept-status the status check above already decided
+        let geo_required = !config.lenient && config.accept_status.is_none();
+        let geo_info = match body.as_deref().map(serde_json::from_slice::<GeoLocationResponse>) {
+            Some(Ok(geo)) if geo.status == "success" || geo_required => geo,
+            Some(Err(e)) if geo_required => return Err(e.into()),
+            // The proxy answered (or --connect-only never asked); only the geo data is missing
+            _ => GeoLocationResponse::unknown(),
+        };
+        let total_time = start_time.elapsed();
 
         if geo_info.status == "success" {
+            let full_geo = config.geo_full.then(|| geo_info.clone());
+            // Follow-up probes only measure latency; the first response already carried the geo data
+            let mut samples = vec![response_time.as_millis()];
+            for _ in 1..config.probe_count {
+                samples.push(staged_fetch(Stage::Geo, &config.url).await?.ttfb.as_millis());
+            }
+            let (response_time_ms, jitter_ms) = latency_stats(&samples);
+
             let city = geo_info.city.unwrap_or_else(|| "Unknown".to_string());
             let country = geo_info.country.unwrap_or_else(|| "Unknown".to_string());
 
+            let exit_ip = geo_info.query.as_deref().and_then(|q| q.parse().ok());
+            let geo_confident = if config.validate_geo {
+                // The exit IP is what the geo API actually located; fall back to the proxy address
+                let lookup_ip = exit_ip.unwrap_or(addr.ip());
+                match geo_cache::secondary_country_code(&config.direct_client, lookup_ip, config.geo_cache.as_deref()).await {
+                    Ok(Some(code)) => Some(geo_info.country_code.as_deref() == Some(code.as_str())),
+                    // No second opinion isn't a disagreement; leave it unknown
+                    Ok(None) | Err(_) => None,
+                }
+            } else {
+                None
+            };
+
+            // The endpoint only has an AAAA record and echoes the caller's address
+            let ipv6_capable = if config.test_ipv6 {
+                let echoed = staged_fetch(Stage::Ipv6, IPV6_TEST_URL).await.ok().and_then(|fetched| fetched.body).map(|body| String::from_utf8_lossy(&body).trim().to_string());
+                Some(echoed.is_some_and(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok()))
+            } else {
+                None
+            };
+
+            let udp_supported = if config.test_udp && protocol == Protocol::Socks5 {
+                Some(udp::socks5_udp_supported(dial, credentials.as_ref(), config.stages.udp, config.nat64).await.unwrap_or(false))
+            } else {
+                None
+            };
+
+            let sni_ok = match &config.sni {
+                Some(check) => {
+                    let handshake = check.run(dial, protocol, credentials.as_ref(), config.local_port_range);
+                    Some(matches!(tokio::time::timeout(config.stages.sni, handshake).await, Ok(Ok(()))))
+                }
+                None => None,
+            };
+
+            // Values are compared rather than names, since echo services often re-case header names
+            let headers_preserved = match &config.echo_url {
+                Some(url) => {
+                    let sent = echo_probe_headers();
+                    let echo = async || -> Result<Vec<u8>> {
+                        if let Some(range) = config.local_port_range {
+                            return Ok(rawproxy::request(dial, protocol, credentials.as_ref(), ECHO_METHOD, url, &sent, range, None).await?.body.unwrap_or_default());
+                        }
+                        let mut request = client.request(reqwest::Method::PUT, url);
+                        for (name, value) in &sent {
+                            request = request.header(name, value);
+                        }
+                        Ok(request.send().await?.bytes().await?.to_vec())
+                    };
+                    let body = tokio::time::timeout(config.stages.headers, echo()).await.ok().and_then(Result::ok).unwrap_or_default();
+                    let contains = |needle: &str| body.windows(needle.len()).any(|window| window == needle.as_bytes());
+                    Some(!body.is_empty() && contains(ECHO_METHOD) && sent.iter().all(|(_, value)| contains(value)))
+                }
+                None => None,
+            };
+
+            let connection_type = classify::classify(
+                geo_info.isp.as_deref(),
+                geo_info.org.as_deref(),
+                geo_info.asn.as_deref(),
+                geo_info.mobile,
+                geo_info.hosting,
+            );
+
             let hostname = lookup_addr(&addr.ip()).unwrap_or_else(|_| "Unknown".to_string());
             Ok(ProxyResult {
                 ip_address: addr.ip(),
-                hostname: hostname,
-                response_time_ms: response_time.as_millis(),
+                port: addr.port(),
+                protocol,
+                hostname,
+                response_time_ms,
+                jitter_ms,
                 location: format!("{}, {}", city, country),
+                country_code: geo_info.country_code,
+                exit_ip,
+                isp: geo_info.isp,
+                asn: geo_info.asn,
+                connection_type,
+                latency_overhead_ms: config.baseline_ms.map(|base| response_time_ms as i128 - base as i128),
+                geo_confident,
+                udp_supported,
+                sni_ok,
+                headers_preserved,
+                ipv6_capable,
+                body_matched,
+                status_code,
+                region: geo_info.region_name,
+                lat: geo_info.lat,
+                lon: geo_info.lon,
+                timezone: geo_info.timezone,
+                attempts: target.attempts + 1,
+                last_error: None,
+                banner: target.banner.clone(),
+                connect_ms,
+                ttfb_ms: config.timings.then_some(response_time.as_millis()),
+                total_ms: config.timings.then_some(total_time.as_millis()),
+                discovered_at: Some(humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string()),
+                credentials: credentials.clone(),
+                geo: full_geo,
+                headers: (config.capture_headers.is_some() || config.capture_all_headers).then(|| {
+                    let mut captured: BTreeMap<String, String> = BTreeMap::new();
+                    for (name, value) in headers {
+                        // Repeated headers are folded into one comma-separated value
+                        captured.entry(name).and_modify(|joined| *joined = format!("{}, {}", joined, value)).or_insert(value);
+                    }
+                    captured
+                }),
             })
         } else {
             let err_msg = geo_info.message.unwrap_or_else(|| "API error".to_string());
             Err(anyhow::anyhow!("Geo API error: {}", err_msg))
         }
     };
-    test_logic.await.map_err(|e| (addr, e))
+    let all_protocols = async || match &config.protocol_order {
+        // Stops at the first protocol that works; otherwise the last protocol's error stands
+        Some(order) => {
+            let mut outcome = Err(anyhow::anyhow!("--protocol-order is empty"));
+            for &protocol in order {
+                outcome = test_logic(protocol).await;
+                if outcome.is_ok() {
+                    break;
+                }
+            }
+            outcome
+        }
+        None => test_logic(config.protocol).await,
+    };
+    let started = Instant::now();
+    let outcome = match config.max_runtime_per_host {
+        // Whatever earlier attempts left of the budget; stage timeouts can't stretch past it
+        Some(limit) => match tokio::time::timeout(limit.saturating_sub(target.elapsed), all_protocols()).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(anyhow::anyhow!("exceeded --max-runtime-per-host of {}", humantime::format_duration(limit))),
+        },
+        None => all_protocols().await,
+    };
+    target.elapsed += started.elapsed();
+    if let Some(cache) = &config.result_cache {
+        let cached = match &outcome {
+            Ok(result) => result_cache::CachedOutcome::Working(Box::new(result.into())),
+            Err(e) => match e.downcast_ref::<Blocked>() {
+                Some(blocked) => result_cache::CachedOutcome::Blocked(blocked.signature.clone()),
+                None => result_cache::CachedOutcome::Failed(e.to_string()),
+            },
+        };
+        cache.lock().unwrap().insert(addr, cached);
+    }
+    outcome.map_err(|e| Box::new((target, e)))
 }
 
-fn display_results(results: &[ProxyResult]) {
-    let mut table = Table::new();
-    table.load_preset(UTF8_FULL).set_header(vec![
-        "Rank",
-        "IP Address",
-        "Hostname",
-        "Response Time",
-        "Location",
-    ]);
+/// `--interactive`: asks which ranks of the table just shown to keep, e.g. `1,3-5`.
+/// An empty answer (or end of input) keeps everything.
+fn pick_by_rank(results: Vec<ProxyResult>) -> Result<Vec<ProxyResult>> {
+    loop {
+        print!("\nSave which proxies? Ranks like 1,3-5 (Enter saves all): ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 || answer.trim().is_empty() {
+            return Ok(results);
+        }
+        match parse_ranks(answer.trim(), results.len()) {
+            Ok(ranks) => return Ok(results.into_iter().enumerate().filter(|(i, _)| ranks.contains(&(i + 1))).map(|(_, result)| result).collect()),
+            Err(e) => eprintln!("[{}] {}", "WARN".yellow().bold(), e),
+        }
+    }
+}
+
+/// Parses a comma-separated list of ranks and `A-B` ranges, each within `1..=count`.
+fn parse_ranks(spec: &str, count: usize) -> Result<BTreeSet<usize>, String> {
+    let mut ranks = BTreeSet::new();
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        let parse = |rank: &str| rank.trim().parse::<usize>().map_err(|_| format!("{:?} is not a rank", rank.trim()));
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == 0 || start > end || end > count {
+            return Err(format!("{} is outside the table's ranks 1-{}", part, count));
+        }
+        ranks.extend(start..=end);
+    }
+    Ok(ranks)
+}
 
-    for (i, result) in results.iter().enumerate() {
-        table.add_row(vec![
-            Cell::new(i + 1),
-            Cell::new(result.ip_address.to_string()),
-            Cell::new(&result.hostname),
-            Cell::new(format!("{} ms", result.response_time_ms)),
-            Cell::new(&result.location),
-        ]);
+/// Explains why a result falls outside --min-latency / --max-latency, if it does.
+fn latency_rejection(filters: &ResultFilters, max_jitter: Option<u128>, result: &ProxyResult) -> Option<String> {
+    match (filters.min_latency, filters.max_latency, max_jitter.zip(result.jitter_ms)) {
+        (Some(min), _, _) if result.response_time_ms < min => {
+            Some(format!("{}ms is suspiciously fast (below {}ms)", result.response_time_ms, min))
+        }
+        (_, Some(max), _) if result.response_time_ms > max => {
+            Some(format!("{}ms is slower than {}ms", result.response_time_ms, max))
+        }
+        (_, _, Some((max, jitter))) if jitter > max => Some(format!("jitter of {}ms exceeds {}ms", jitter, max)),
+        _ => None,
     }
+}
 
-    println!("{table}");
+/// The `--rank-by score` penalty for a result; lower ranks higher. Latency and jitter count
+/// per millisecond, and every extra attempt the proxy needed (`--second-pass`) adds
+/// `--weight-retry` milliseconds' worth. Jitter only exists with `--probe-count` above 1.
+fn quality_score(ranking: &Ranking, result: &ProxyResult) -> f64 {
+    let latency = result.response_time_ms as f64;
+    let jitter = result.jitter_ms.unwrap_or(0) as f64;
+    let retries = result.attempts.saturating_sub(1) as f64;
+    ranking.weight_latency * latency + ranking.weight_jitter * jitter + ranking.weight_retry * retries
 }
 
-fn save_to_csv(path: &PathBuf, results: &[ProxyResult]) -> Result<()> {
-    let mut wtr = csv::Writer::from_path(path)?;
-    for result in results {
-        wtr.serialize(result)?;
+/// Working proxies gathered during the run. With `--top N` they sit in a max-heap on
+/// latency, so once N are held each newcomer evicts the slowest.
+struct KeptResults {
+    top: Option<usize>,
+    heap: BinaryHeap<ByLatency>,
+    found: usize,
+}
+
+impl KeptResults {
+    fn new(top: Option<usize>) -> Self {
+        Self { top, heap: BinaryHeap::new(), found: 0 }
     }
-    wtr.flush()?;
-    Ok(())
-}
\ No newline at end of file
+
+    /// Adds `result`, returning whichever result `--top` pushed out (possibly `result` itself).
+    fn push(&mut self, result: ProxyResult) -> Option<ProxyResult> {
+        self.found += 1;
+        self.heap.push(ByLatency(result));
+        match self.top {
+            Some(top) if self.heap.len() > top => self.heap.pop().map(|ByLatency(evicted)| evicted),
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// The proxies held right now, fastest first
+    fn snapshot(&self) -> Vec<ProxyResult> {
+        let mut results: Vec<ProxyResult> = self.heap.iter().map(|ByLatency(result)| result.clone()).collect();
+        results.sort_by_key(|result| result.response_time_ms);
+        results
+    }
+
+    /// Every working proxy seen, including ones `--top` has since dropped
+    fn found(&self) -> usize {
+        self.found
+    }
+
+    fn into_vec(self) -> Vec<ProxyResult> {
+        self.heap.into_vec().into_iter().map(|ByLatency(result)| result).collect()
+    }
+}
+
+/// How many held results come from each country and ASN, for `--per-country-limit` and
+/// `--per-asn-limit` and their summaries.
+#[derive(Default)]
+struct HeldCounts {
+    per_country: BTreeMap<String, usize>,
+    per_asn: BTreeMap<String, usize>,
+}
+
+impl HeldCounts {
+    fn add(&mut self, result: &ProxyResult) {
+        *self.per_country.entry(country_key(result)).or_default() += 1;
+        *self.per_asn.entry(asn_key(result)).or_default() += 1;
+    }
+
+    fn remove(&mut self, result: &ProxyResult) {
+        for (counts, key) in [(&mut self.per_country, country_key(result)), (&mut self.per_asn, asn_key(result))] {
+            if let Some(n) = counts.get_mut(&key) {
+                *n -= 1;
+                if *n == 0 {
+                    counts.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+fn country_key(result: &ProxyResult) -> String {
+    result.country_code.clone().unwrap_or_else(|| "??".to_string())
+}
+
+fn asn_key(result: &ProxyResult) -> String {
+    result.asn.clone().unwrap_or_else(|| "??".to_string())
+}
+
+struct ByLatency(ProxyResult);
+
+impl PartialEq for ByLatency {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.response_time_ms == other.0.response_time_ms
+    }
+}
+
+impl Eq for ByLatency {}
+
+impl PartialOrd for ByLatency {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByLatency {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.response_time_ms.cmp(&other.0.response_time_ms)
+    }
+}
+
+/// Mean and population standard deviation of the probe latencies, both rounded to
+/// whole milliseconds. A single sample has no jitter.
+fn latency_stats(samples: &[u128]) -> (u128, Option<u128>) {
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<u128>() as f64 / n;
+    if samples.len() < 2 {
+        return (mean.round() as u128, None);
+    }
+    let variance = samples.iter().map(|&ms| (ms as f64 - mean).powi(2)).sum::<f64>() / n;
+    (mean.round() as u128, Some(variance.sqrt().round() as u128))
+}
+
+/// Counts proxies that egress from the same public address as a faster one. Results must
+/// already be sorted by latency so that collapsing keeps the fastest front-end of each exit IP.
+fn report_shared_exit_ips(results: &mut Vec<ProxyResult>, collapse: bool) -> usize {
+    let mut seen = HashSet::new();
+    let shared = results
+        .iter()
+        .filter(|result| result.exit_ip.is_some_and(|ip| !seen.insert(ip)))
+        .count();
+
+    if shared > 0 && collapse {
+        let mut seen = HashSet::new();
+        results.retain(|result| result.exit_ip.is_none_or(|ip| seen.insert(ip)));
+    }
+    shared
+}
+
+fn write_results_to_file(path: &Path, format: OutputFormat, append: bool, gzip: bool, options: &OutputOptions, results: &[ProxyResult]) -> Result<()> {
+    if !append {
+        return output::write_atomically(path, |writer| match gzip {
+            true => {
+                let mut encoder = GzEncoder::new(writer, Compression::default());
+                output::sink_for(format, Box::new(&mut encoder), options).write_results(results)?;
+                encoder.finish()?;
+                Ok(())
+            }
+            false => output::sink_for(format, Box::new(writer), options).write_results(results),
+        });
+    }
+
+    if format != OutputFormat::Csv {
+        anyhow::bail!("--append is only supported for CSV output");
+    }
+    // Only write the header row when starting a fresh file
+    let is_new = std::fs::metadata(path).map_or(true, |meta| meta.len() == 0);
+    output::ensure_parent_dir(path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {} for appending", path.display()))?;
+    if gzip {
+        // Concatenated gzip members decompress as one stream, so each run adds its own member
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        CsvSink::with_headers(&mut encoder, is_new, options.csv_fields.as_deref()).write_results(results)?;
+        encoder.finish()?.flush()?;
+        return Ok(());
+    }
+    CsvSink::with_headers(BufWriter::new(file), is_new, options.csv_fields.as_deref()).write_results(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_ranges_accept_codes_and_ranges() {
+        let accept: StatusRanges = "200-299, 301,302".parse().unwrap();
+        for status in [200, 250, 299, 301, 302] {
+            assert!(accept.contains(status), "{} should be accepted", status);
+        }
+        for status in [199, 300, 303, 404] {
+            assert!(!accept.contains(status), "{} should be rejected", status);
+        }
+    }
+
+    #[test]
+    fn status_ranges_allow_the_edges_of_the_status_space() {
+        let accept: StatusRanges = "100-599".parse().unwrap();
+        assert!(accept.contains(100) && accept.contains(599));
+        let single: StatusRanges = "204-204".parse().unwrap();
+        assert!(single.contains(204) && !single.contains(205));
+    }
+
+    #[test]
+    fn status_ranges_reject_bad_specs() {
+        let error = |spec: &str| spec.parse::<StatusRanges>().unwrap_err();
+        assert_eq!(error("200-299,3xx"), r#"invalid HTTP status "3xx""#);
+        assert_eq!(error("99"), r#"invalid HTTP status "99""#);
+        assert_eq!(error("600"), r#"invalid HTTP status "600""#);
+        assert_eq!(error("200-"), r#"invalid HTTP status """#);
+        assert_eq!(error("299-200"), r#""299-200" ends before it starts"#);
+        assert_eq!(error(" , "), "expected at least one status code");
+    }
+
+    #[test]
+    fn stage_overrides_parse_each_stage() {
+        let StageOverrides(overrides) = "connect=2s, geo=1500ms,ipv6=3,udp=1m,sni=0,headers=250ms".parse().unwrap();
+        assert_eq!(
+            overrides,
+            [
+                (Stage::Connect, Duration::from_secs(2)),
+                (Stage::Geo, Duration::from_millis(1500)),
+                (Stage::Ipv6, Duration::from_secs(3)),
+                (Stage::Udp, Duration::from_secs(60)),
+                (Stage::Sni, Duration::ZERO),
+                (Stage::Headers, Duration::from_millis(250)),
+            ]
+        );
+    }
+
+    #[test]
+    fn stage_overrides_skip_empty_entries() {
+        let StageOverrides(overrides) = ",connect = 5s,,".parse().unwrap();
+        assert_eq!(overrides, [(Stage::Connect, Duration::from_secs(5))]);
+        assert!("".parse::<StageOverrides>().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn stage_overrides_reject_bad_entries() {
+        let error = |spec: &str| spec.parse::<StageOverrides>().unwrap_err();
+        assert_eq!(error("connect"), r#"expected STAGE=DURATION, got "connect""#);
+        assert_eq!(error("dns=2s"), r#"unknown stage "dns" (expected one of connect, geo, ipv6, udp, sni, headers)"#);
+        assert!(error("geo=soon").starts_with("geo: "));
+        assert!(error("connect=2s,udp=").starts_with("udp: "));
+    }
+
+    #[test]
+    fn parse_ranks_accepts_ranks_and_ranges() {
+        let ranks = parse_ranks("1, 3-5,4,10", 10).unwrap();
+        assert_eq!(ranks.into_iter().collect::<Vec<_>>(), [1, 3, 4, 5, 10]);
+        assert_eq!(parse_ranks("7-7", 7).unwrap().into_iter().collect::<Vec<_>>(), [7]);
+        assert!(parse_ranks(" , ", 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_ranks_reject_out_of_table_and_bad_ranks() {
+        let error = |spec: &str| parse_ranks(spec, 5).unwrap_err();
+        assert_eq!(error("0"), "0 is outside the table's ranks 1-5");
+        assert_eq!(error("6"), "6 is outside the table's ranks 1-5");
+        assert_eq!(error("2-6"), "2-6 is outside the table's ranks 1-5");
+        assert_eq!(error("4-2"), "4-2 is outside the table's ranks 1-5");
+        assert_eq!(error("first"), r#""first" is not a rank"#);
+        assert_eq!(error("2-"), r#""" is not a rank"#);
+        assert_eq!(error("-1"), r#""" is not a rank"#);
+    }
+
+    #[test]
+    fn latency_stats_single_sample_has_no_jitter() {
+        assert_eq!(latency_stats(&[120]), (120, None));
+    }
+
+    #[test]
+    fn latency_stats_use_population_deviation() {
+        assert_eq!(latency_stats(&[100, 100, 100]), (100, Some(0)));
+        assert_eq!(latency_stats(&[100, 200]), (150, Some(50)));
+        // Mean 5, squared deviations sum to 32 over 8 samples
+        assert_eq!(latency_stats(&[2, 4, 4, 4, 5, 5, 7, 9]), (5, Some(2)));
+    }
+
+    #[test]
+    fn latency_stats_round_to_whole_milliseconds() {
+        // Mean 10.5 rounds up; deviation sqrt(0.25) = 0.5 rounds up too
+        assert_eq!(latency_stats(&[10, 11]), (11, Some(1)));
+        // Mean 10.33.. rounds down; deviation sqrt(2/9) = 0.47.. rounds down
+        assert_eq!(latency_stats(&[10, 10, 11]), (10, Some(0)));
+    }
+}
@@ -0,0 +1,64 @@
+// src/nat64.rs
+
+use crate::ScannerError;
+use ipnet::Ipv6Net;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// `--nat64-prefix`: the /96 a NAT64 gateway translates, e.g. the well-known `64:ff9b::/96`.
+/// IPv4 addresses are embedded in its last 32 bits (RFC 6052).
+#[derive(Debug, Clone, Copy)]
+pub struct Nat64Prefix(Ipv6Net);
+
+impl std::str::FromStr for Nat64Prefix {
+    type Err = ScannerError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| ScannerError::InvalidCidr { input: value.to_string(), reason };
+        let net: Ipv6Net = value.parse().map_err(|e: ipnet::AddrParseError| invalid(e.to_string()))?;
+        if net.prefix_len() != 96 {
+            return Err(invalid("only /96 NAT64 prefixes are supported".to_string()));
+        }
+        Ok(Self(net.trunc()))
+    }
+}
+
+impl Nat64Prefix {
+    /// The IPv6 address that reaches an IPv4 one through the gateway; IPv6 addresses pass through.
+    pub fn synthesize(&self, addr: SocketAddr) -> SocketAddr {
+        match addr.ip() {
+            IpAddr::V4(v4) => {
+                let bits = u128::from(self.0.network()) | u128::from(u32::from(v4));
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(bits)), addr.port())
+            }
+            IpAddr::V6(_) => addr,
+        }
+    }
+}
+
+/// DNS for the direct client (baseline, geo cross-checks) on IPv6-only hosts: synthesizes
+/// NAT64 addresses for names without AAAA records, and with `--prefer-ipv6` tries IPv6 first.
+pub struct Resolver {
+    pub nat64: Option<Nat64Prefix>,
+    pub prefer_ipv6: bool,
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let (nat64, prefer_ipv6) = (self.nat64, self.prefer_ipv6);
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            // A AAAA record means the host is reachable natively, so only IPv4-only names are translated
+            if let Some(prefix) = nat64
+                && !addrs.iter().any(SocketAddr::is_ipv6)
+            {
+                addrs = addrs.into_iter().map(|addr| prefix.synthesize(addr)).collect();
+            }
+            if prefer_ipv6 {
+                addrs.sort_by_key(SocketAddr::is_ipv4);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
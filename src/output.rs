@@ -0,0 +1,834 @@
+// src/output.rs
+
+use crate::classify::ConnectionType;
+use crate::{Protocol, ProxyResult};
+use anyhow::{Context, Result};
+use comfy_table::{presets, Cell, Table};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use subnet_scanner::geo::GeoLocationResponse;
+
+// --- Output Formats ---
+
+/// The formats results can be written in, selected by `--format`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A human-readable table
+    Table,
+    /// Comma-separated values with a header row
+    Csv,
+    /// A versioned JSON document with scan metadata and results
+    Json,
+    /// A Clash/mihomo `proxies:` YAML block
+    Clash,
+    /// One whitespace-separated line per proxy: `ip:port latency_ms country protocol`
+    Line,
+    /// A shell array to `source` or `eval`: `PROXIES=( 'ip:port' ... )`
+    Env,
+    /// A Proxy Auto-Config script for browsers, spreading hosts across the proxies
+    Pac,
+}
+
+impl OutputFormat {
+    /// Guesses a file's format from its extension, looking past a trailing `.gz`.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let path = match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => path.file_stem().map(Path::new)?,
+            _ => path,
+        };
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Clash),
+            "txt" => Some(Self::Line),
+            "pac" => Some(Self::Pac),
+            _ => None,
+        }
+    }
+}
+
+/// Visual weight of the results table, selected by `--table-style`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableStyle {
+    /// Box-drawing characters (the default)
+    #[default]
+    Utf8,
+    /// Plain ASCII borders
+    Ascii,
+    /// Columns separated by whitespace only
+    Borderless,
+    /// A Markdown table
+    Markdown,
+}
+
+impl TableStyle {
+    fn preset(self) -> &'static str {
+        match self {
+            TableStyle::Utf8 => presets::UTF8_FULL,
+            TableStyle::Ascii => presets::ASCII_FULL,
+            TableStyle::Borderless => presets::NOTHING,
+            TableStyle::Markdown => presets::ASCII_MARKDOWN,
+        }
+    }
+}
+
+/// A destination that knows how to render a set of results in one format.
+pub trait OutputSink {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()>;
+}
+
+/// Settings that shape how results are rendered, shared by every sink.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Show the per-stage timing columns in the table (`--timings`)
+    pub timings: bool,
+    pub table_style: TableStyle,
+    /// Split the table into one sub-table per containing network of this prefix length (`--group-by`)
+    pub group_by: Option<u8>,
+    /// Restrict CSV output to these columns (`--csv-fields`)
+    pub csv_fields: Option<Vec<CsvField>>,
+    /// Describes the run in the JSON envelope
+    pub scan: ScanMeta,
+}
+
+/// Builds the sink for `format` on top of an arbitrary writer.
+pub fn sink_for<'a>(format: OutputFormat, writer: Box<dyn Write + 'a>, options: &'a OutputOptions) -> Box<dyn OutputSink + 'a> {
+    match format {
+        OutputFormat::Table => Box::new(TableSink::new(writer, options)),
+        OutputFormat::Csv => Box::new(CsvSink::new(writer, options.csv_fields.as_deref())),
+        OutputFormat::Json => Box::new(JsonSink::new(writer, &options.scan)),
+        OutputFormat::Clash => Box::new(ClashSink::new(writer)),
+        OutputFormat::Line => Box::new(LineSink::new(writer)),
+        OutputFormat::Env => Box::new(EnvSink::new(writer)),
+        OutputFormat::Pac => Box::new(PacSink::new(writer)),
+    }
+}
+
+// --- Stable JSON Contract ---
+
+/// Bump whenever a field in the JSON envelope is renamed, removed or changes meaning.
+/// Adding new optional fields does not require a bump.
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// Top-level JSON document. Field names here are a public contract; internal structs
+/// are mapped onto it so refactors don't leak into downstream tooling.
+#[derive(Debug, Serialize)]
+struct JsonEnvelope<'a> {
+    schema_version: u32,
+    scan: &'a ScanMeta,
+    results: Vec<JsonResult<'a>>,
+}
+
+/// What was scanned, how, and by which build of the tool.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanMeta {
+    pub tool_version: String,
+    /// The full argument vector, program name included
+    pub command_line: Vec<String>,
+    /// When the scan began, RFC 3339 in UTC
+    pub started_at: String,
+    pub source: String,
+    /// The first of `ports`, kept for consumers that predate multi-port scans
+    pub port: u16,
+    pub ports: Vec<u16>,
+    pub scan_timeout_ms: u64,
+    pub test_timeout_secs: u64,
+}
+
+/// The `--summary` sidecar: the run's metadata and totals without per-proxy rows.
+#[derive(Debug, Serialize)]
+pub struct RunSummary<'a> {
+    schema_version: u32,
+    scan: &'a ScanMeta,
+    working: usize,
+    failed: usize,
+    /// Reached the test URL but got a `--block-signature` page
+    blocked: usize,
+    skipped: usize,
+    per_country: BTreeMap<&'a str, usize>,
+}
+
+impl<'a> RunSummary<'a> {
+    pub fn new(scan: &'a ScanMeta, results: &'a [ProxyResult], failed: usize, blocked: usize, skipped: usize) -> Self {
+        let mut per_country = BTreeMap::new();
+        for result in results {
+            *per_country.entry(result.country_code.as_deref().unwrap_or("??")).or_default() += 1;
+        }
+        Self { schema_version: JSON_SCHEMA_VERSION, scan, working: results.len(), failed, blocked, skipped, per_country }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonResult<'a> {
+    ip: IpAddr,
+    port: u16,
+    protocol: Protocol,
+    hostname: &'a str,
+    response_time_ms: u128,
+    jitter_ms: Option<u128>,
+    location: &'a str,
+    country_code: Option<&'a str>,
+    exit_ip: Option<IpAddr>,
+    isp: Option<&'a str>,
+    asn: Option<&'a str>,
+    connection_type: ConnectionType,
+    latency_overhead_ms: Option<i128>,
+    geo_confident: Option<bool>,
+    udp_supported: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sni_ok: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers_preserved: Option<bool>,
+    ipv6_capable: Option<bool>,
+    body_matched: Option<bool>,
+    status_code: Option<u16>,
+    attempts: u32,
+    last_error: Option<&'a str>,
+    discovered_at: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    banner: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geo: Option<&'a GeoLocationResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<&'a BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<JsonTimings>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonTimings {
+    connect_ms: Option<u128>,
+    ttfb_ms: Option<u128>,
+    total_ms: Option<u128>,
+}
+
+impl<'a> From<&'a ProxyResult> for JsonResult<'a> {
+    fn from(result: &'a ProxyResult) -> Self {
+        let timed = result.connect_ms.is_some() || result.ttfb_ms.is_some() || result.total_ms.is_some();
+        Self {
+            ip: result.ip_address,
+            port: result.port,
+            protocol: result.protocol,
+            hostname: &result.hostname,
+            response_time_ms: result.response_time_ms,
+            jitter_ms: result.jitter_ms,
+            location: &result.location,
+            country_code: result.country_code.as_deref(),
+            exit_ip: result.exit_ip,
+            isp: result.isp.as_deref(),
+            asn: result.asn.as_deref(),
+            connection_type: result.connection_type,
+            latency_overhead_ms: result.latency_overhead_ms,
+            geo_confident: result.geo_confident,
+            udp_supported: result.udp_supported,
+            sni_ok: result.sni_ok,
+            headers_preserved: result.headers_preserved,
+            ipv6_capable: result.ipv6_capable,
+            body_matched: result.body_matched,
+            status_code: result.status_code,
+            attempts: result.attempts,
+            last_error: result.last_error.as_deref(),
+            discovered_at: result.discovered_at.as_deref(),
+            banner: result.banner.as_deref(),
+            geo: result.geo.as_ref(),
+            headers: result.headers.as_ref(),
+            timings: timed.then_some(JsonTimings {
+                connect_ms: result.connect_ms,
+                ttfb_ms: result.ttfb_ms,
+                total_ms: result.total_ms,
+            }),
+        }
+    }
+}
+
+// --- Reading Saved Results ---
+
+/// A `results` element of a saved JSON document, owned so it can be read back.
+#[derive(Debug, Deserialize)]
+struct JsonRecord {
+    ip: IpAddr,
+    port: u16,
+    protocol: Protocol,
+    hostname: String,
+    response_time_ms: u128,
+    jitter_ms: Option<u128>,
+    location: String,
+    country_code: Option<String>,
+    exit_ip: Option<IpAddr>,
+    isp: Option<String>,
+    asn: Option<String>,
+    connection_type: ConnectionType,
+    latency_overhead_ms: Option<i128>,
+    geo_confident: Option<bool>,
+    udp_supported: Option<bool>,
+    #[serde(default)]
+    sni_ok: Option<bool>,
+    #[serde(default)]
+    headers_preserved: Option<bool>,
+    ipv6_capable: Option<bool>,
+    body_matched: Option<bool>,
+    status_code: Option<u16>,
+    attempts: u32,
+    last_error: Option<String>,
+    #[serde(default)]
+    discovered_at: Option<String>,
+    #[serde(default)]
+    banner: Option<String>,
+    #[serde(default)]
+    geo: Option<GeoLocationResponse>,
+    #[serde(default)]
+    headers: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    timings: Option<JsonRecordTimings>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRecordTimings {
+    connect_ms: Option<u128>,
+    ttfb_ms: Option<u128>,
+    total_ms: Option<u128>,
+}
+
+impl From<JsonRecord> for ProxyResult {
+    fn from(record: JsonRecord) -> Self {
+        let timings = record.timings.unwrap_or(JsonRecordTimings { connect_ms: None, ttfb_ms: None, total_ms: None });
+        let geo = record.geo.as_ref();
+        Self {
+            ip_address: record.ip,
+            port: record.port,
+            protocol: record.protocol,
+            hostname: record.hostname,
+            response_time_ms: record.response_time_ms,
+            jitter_ms: record.jitter_ms,
+            location: record.location,
+            country_code: record.country_code,
+            exit_ip: record.exit_ip,
+            isp: record.isp,
+            asn: record.asn,
+            connection_type: record.connection_type,
+            latency_overhead_ms: record.latency_overhead_ms,
+            geo_confident: record.geo_confident,
+            udp_supported: record.udp_supported,
+            sni_ok: record.sni_ok,
+            headers_preserved: record.headers_preserved,
+            ipv6_capable: record.ipv6_capable,
+            body_matched: record.body_matched,
+            status_code: record.status_code,
+            // Only --geo-full documents carry the finer location fields
+            region: geo.and_then(|geo| geo.region_name.clone()),
+            lat: geo.and_then(|geo| geo.lat),
+            lon: geo.and_then(|geo| geo.lon),
+            timezone: geo.and_then(|geo| geo.timezone.clone()),
+            attempts: record.attempts,
+            last_error: record.last_error,
+            banner: record.banner,
+            connect_ms: timings.connect_ms,
+            ttfb_ms: timings.ttfb_ms,
+            total_ms: timings.total_ms,
+            discovered_at: record.discovered_at,
+            credentials: None,
+            geo: record.geo,
+            headers: record.headers,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonDocument {
+    schema_version: u32,
+    #[serde(default)]
+    scan: ScanMeta,
+    results: Vec<JsonRecord>,
+}
+
+/// Reads results written by `--format json`, returning the scan they came from.
+pub fn read_json_results(reader: impl Read) -> Result<(ScanMeta, Vec<ProxyResult>)> {
+    let document: JsonDocument = serde_json::from_reader(reader)?;
+    if document.schema_version > JSON_SCHEMA_VERSION {
+        anyhow::bail!("Results use JSON schema version {}, newer than this build understands ({})", document.schema_version, JSON_SCHEMA_VERSION);
+    }
+    Ok((document.scan, document.results.into_iter().map(ProxyResult::from).collect()))
+}
+
+/// Reads results written as CSV with every column (not a `--csv-fields` subset).
+pub fn read_csv_results(reader: impl Read) -> Result<Vec<ProxyResult>> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    let mut results = Vec::new();
+    for (row, record) in rdr.deserialize().enumerate() {
+        // Row 1 is the header, so data starts on line 2
+        results.push(record.with_context(|| format!("Malformed results row on line {}", row + 2))?);
+    }
+    Ok(results)
+}
+
+/// Renders a text histogram of response times across fixed latency bands.
+pub fn latency_histogram(results: &[ProxyResult]) -> String {
+    const BANDS: [(&str, u128); 5] = [
+        ("<100ms", 100),
+        ("100-500ms", 500),
+        ("500ms-1s", 1_000),
+        ("1-5s", 5_000),
+        (">5s", u128::MAX),
+    ];
+    const BAR_WIDTH: usize = 40;
+
+    let mut counts = [0usize; BANDS.len()];
+    for result in results {
+        let band = BANDS.iter().position(|&(_, upper)| result.response_time_ms < upper).unwrap_or(BANDS.len() - 1);
+        counts[band] += 1;
+    }
+
+    let max = counts.iter().copied().max().unwrap_or(0).max(1);
+    let mut lines = vec!["Latency distribution:".to_string()];
+    for ((label, _), count) in BANDS.iter().zip(counts) {
+        let bar = "#".repeat(count * BAR_WIDTH / max);
+        lines.push(format!("  {:>10} | {:<width$} {}", label, bar, count, width = BAR_WIDTH));
+    }
+    lines.join("\n")
+}
+
+/// Creates the directory `path` will be written into, so a typo'd or not-yet-existing
+/// output folder doesn't surface as a bare "No such file or directory" after a long scan.
+pub fn ensure_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory {}", parent.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Writes `path` via a temporary sibling file that is renamed into place only once
+/// everything succeeded, so a failed or killed run never clobbers a previous results file.
+pub fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Output path has no file name: {}", path.display()))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp-{}", file_name.to_string_lossy(), std::process::id()));
+    ensure_parent_dir(path)?;
+
+    let result = (|| -> Result<()> {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// --- Open-Address Stream (--no-test) ---
+
+/// Writes each open address the moment the scan finds it, for `--no-test` pipelines.
+/// Only the formats that make sense for an untested address are accepted.
+pub struct AddressStream<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    written: usize,
+}
+
+impl<W: Write> AddressStream<W> {
+    pub fn new(mut writer: W, format: OutputFormat) -> Result<Self> {
+        match format {
+            OutputFormat::Line | OutputFormat::Json => {}
+            OutputFormat::Csv => writeln!(writer, "IP Address,Port")?,
+            _ => anyhow::bail!("--no-test only writes bare addresses; use --format line, csv or json"),
+        }
+        Ok(Self { writer, format, written: 0 })
+    }
+
+    /// Writes one address and flushes, so the next stage of a pipeline sees it right away.
+    /// JSON is written as one object per line, stamped with when the port was found open.
+    pub fn write(&mut self, addr: SocketAddr) -> Result<()> {
+        match self.format {
+            OutputFormat::Csv => writeln!(self.writer, "{},{}", addr.ip(), addr.port())?,
+            OutputFormat::Json => {
+                let open_at = humantime::format_rfc3339_millis(std::time::SystemTime::now()).to_string();
+                writeln!(self.writer, "{}", serde_json::json!({ "ip": addr.ip(), "port": addr.port(), "open_at": open_at }))?
+            }
+            _ => writeln!(self.writer, "{}", addr)?,
+        }
+        self.writer.flush()?;
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+}
+
+// --- Clipboard (--clipboard) ---
+
+/// One `scheme://ip:port` per line, the form most proxy-aware tools accept pasted.
+/// Credentials are left out, as with `--format env`.
+pub fn proxy_list(results: &[ProxyResult]) -> String {
+    results.iter().map(|result| format!("{}://{}\n", result.protocol.scheme(), std::net::SocketAddr::new(result.ip_address, result.port))).collect()
+}
+
+/// Hands `text` to the platform's clipboard tool, trying each known one in turn. Fails on
+/// headless systems, where none of them is installed or has a display to talk to.
+pub fn copy_to_clipboard(text: &str) -> Result<()> {
+    const TOOLS: &[(&str, &[&str])] = &[
+        ("pbcopy", &[]),
+        ("wl-copy", &[]),
+        ("xclip", &["-selection", "clipboard"]),
+        ("xsel", &["--clipboard", "--input"]),
+        ("clip.exe", &[]),
+    ];
+    for (tool, args) in TOOLS {
+        let Ok(mut child) = std::process::Command::new(tool)
+            .args(*args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+        else {
+            continue;
+        };
+        // Dropping stdin closes the pipe so the tool sees end of input
+        child.stdin.take().context("clipboard tool has no stdin")?.write_all(text.as_bytes())?;
+        if child.wait()?.success() {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("no working clipboard tool found (tried pbcopy, wl-copy, xclip, xsel, clip.exe)")
+}
+
+// --- Sink Implementations ---
+
+pub struct TableSink<'a, W: Write> {
+    writer: W,
+    options: &'a OutputOptions,
+}
+
+impl<'a, W: Write> TableSink<'a, W> {
+    pub fn new(writer: W, options: &'a OutputOptions) -> Self {
+        Self { writer, options }
+    }
+}
+
+impl<W: Write> TableSink<'_, W> {
+    fn render(&self, results: &[&ProxyResult]) -> Table {
+        let mut table = Table::new();
+        let mut header = vec!["Rank", "IP Address", "Hostname", "Response Time", "Location", "Exit IP"];
+        if self.options.timings {
+            header.extend(["Connect", "TTFB", "Total"]);
+        }
+        table.load_preset(self.options.table_style.preset()).set_header(header);
+
+        for (i, result) in results.iter().enumerate() {
+            let mut row = vec![
+                Cell::new(i + 1),
+                Cell::new(result.ip_address.to_string()),
+                Cell::new(&result.hostname),
+                Cell::new({
+                    let mut cell = format!("{} ms", result.response_time_ms);
+                    if let Some(jitter) = result.jitter_ms {
+                        cell.push_str(&format!(" ±{} ms", jitter));
+                    }
+                    if let Some(overhead) = result.latency_overhead_ms {
+                        cell.push_str(&format!(" ({:+} ms)", overhead));
+                    }
+                    cell
+                }),
+                Cell::new(&result.location),
+                Cell::new(result.exit_ip.map_or_else(|| "Unknown".to_string(), |ip| ip.to_string())),
+            ];
+            if self.options.timings {
+                for stage in [result.connect_ms, result.ttfb_ms, result.total_ms] {
+                    row.push(Cell::new(stage.map_or_else(|| "-".to_string(), |ms| format!("{} ms", ms))));
+                }
+            }
+            table.add_row(row);
+        }
+        table
+    }
+}
+
+impl<W: Write> OutputSink for TableSink<'_, W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        match self.options.group_by {
+            Some(prefix_len) => {
+                // Buckets keep the incoming (latency) order; the networks themselves print in address order
+                let mut groups: BTreeMap<IpNet, Vec<&ProxyResult>> = BTreeMap::new();
+                for result in results {
+                    let prefix_len = prefix_len.min(if result.ip_address.is_ipv4() { 32 } else { 128 });
+                    let network = IpNet::new(result.ip_address, prefix_len).expect("prefix length is clamped").trunc();
+                    groups.entry(network).or_default().push(result);
+                }
+                for (network, members) in groups {
+                    writeln!(self.writer, "\n{} ({} working)", network, members.len())?;
+                    writeln!(self.writer, "{}", self.render(&members))?;
+                }
+            }
+            None => writeln!(self.writer, "{}", self.render(&results.iter().collect::<Vec<_>>()))?,
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Columns `--csv-fields` can pick, in the order given on the command line.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsvField {
+    Ip,
+    Port,
+    Protocol,
+    Hostname,
+    Latency,
+    Jitter,
+    Location,
+    Country,
+    Region,
+    ExitIp,
+    Isp,
+    Asn,
+    Type,
+    Timezone,
+    Attempts,
+    DiscoveredAt,
+}
+
+impl CsvField {
+    /// The header matches the column name the full CSV uses for the same data.
+    fn header(self) -> &'static str {
+        match self {
+            CsvField::Ip => "IP Address",
+            CsvField::Port => "Port",
+            CsvField::Protocol => "Protocol",
+            CsvField::Hostname => "Hostname",
+            CsvField::Latency => "Response Time (ms)",
+            CsvField::Jitter => "Jitter (ms)",
+            CsvField::Location => "Location",
+            CsvField::Country => "Country Code",
+            CsvField::Region => "Region",
+            CsvField::ExitIp => "Exit IP",
+            CsvField::Isp => "ISP",
+            CsvField::Asn => "ASN",
+            CsvField::Type => "Connection Type",
+            CsvField::Timezone => "Timezone",
+            CsvField::Attempts => "Attempts",
+            CsvField::DiscoveredAt => "Discovered At",
+        }
+    }
+
+    fn value(self, result: &ProxyResult) -> String {
+        fn or_empty<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_default()
+        }
+        match self {
+            CsvField::Ip => result.ip_address.to_string(),
+            CsvField::Port => result.port.to_string(),
+            CsvField::Protocol => result.protocol.scheme().to_string(),
+            CsvField::Hostname => result.hostname.clone(),
+            CsvField::Latency => result.response_time_ms.to_string(),
+            CsvField::Jitter => or_empty(&result.jitter_ms),
+            CsvField::Location => result.location.clone(),
+            CsvField::Country => or_empty(&result.country_code),
+            CsvField::Region => or_empty(&result.region),
+            CsvField::ExitIp => or_empty(&result.exit_ip),
+            CsvField::Isp => or_empty(&result.isp),
+            CsvField::Asn => or_empty(&result.asn),
+            CsvField::Type => format!("{:?}", result.connection_type).to_lowercase(),
+            CsvField::Timezone => or_empty(&result.timezone),
+            CsvField::Attempts => result.attempts.to_string(),
+            CsvField::DiscoveredAt => or_empty(&result.discovered_at),
+        }
+    }
+}
+
+pub struct CsvSink<'a, W: Write> {
+    writer: csv::Writer<W>,
+    has_headers: bool,
+    /// `--csv-fields`; `None` writes every column
+    fields: Option<&'a [CsvField]>,
+}
+
+impl<'a, W: Write> CsvSink<'a, W> {
+    pub fn new(writer: W, fields: Option<&'a [CsvField]>) -> Self {
+        Self::with_headers(writer, true, fields)
+    }
+
+    /// Like `new`, but lets appending callers skip the header row.
+    pub fn with_headers(writer: W, has_headers: bool, fields: Option<&'a [CsvField]>) -> Self {
+        let writer = csv::WriterBuilder::new().has_headers(has_headers).from_writer(writer);
+        Self { writer, has_headers, fields }
+    }
+}
+
+impl<W: Write> OutputSink for CsvSink<'_, W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        match self.fields {
+            Some(fields) => {
+                if self.has_headers {
+                    self.writer.write_record(fields.iter().map(|field| field.header()))?;
+                }
+                for result in results {
+                    self.writer.write_record(fields.iter().map(|field| field.value(result)))?;
+                }
+            }
+            None => {
+                for result in results {
+                    self.writer.serialize(result)?;
+                }
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct JsonSink<'a, W: Write> {
+    writer: W,
+    scan: &'a ScanMeta,
+}
+
+impl<'a, W: Write> JsonSink<'a, W> {
+    pub fn new(writer: W, scan: &'a ScanMeta) -> Self {
+        Self { writer, scan }
+    }
+}
+
+impl<W: Write> OutputSink for JsonSink<'_, W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        let envelope = JsonEnvelope {
+            schema_version: JSON_SCHEMA_VERSION,
+            scan: self.scan,
+            results: results.iter().map(JsonResult::from).collect(),
+        };
+        serde_json::to_writer_pretty(&mut self.writer, &envelope)?;
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Emits a ready-to-paste Clash `proxies:` block, one entry per working proxy.
+pub struct ClashSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ClashSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for ClashSink<W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        writeln!(self.writer, "proxies:")?;
+        for result in results {
+            let country = result.country_code.as_deref().unwrap_or("XX");
+            let name = format!("{}-{}:{}", country, result.ip_address, result.port);
+            writeln!(self.writer, "  - name: {}", yaml_quote(&name))?;
+            writeln!(self.writer, "    type: {}", result.protocol.scheme())?;
+            writeln!(self.writer, "    server: {}", yaml_quote(&result.ip_address.to_string()))?;
+            writeln!(self.writer, "    port: {}", result.port)?;
+            if let Some(credentials) = &result.credentials {
+                writeln!(self.writer, "    username: {}", yaml_quote(&credentials.username))?;
+                writeln!(self.writer, "    password: {}", yaml_quote(&credentials.password))?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Compact, greppable output: fixed columns with no borders or header.
+pub struct LineSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> LineSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for LineSink<W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        for result in results {
+            let addr = std::net::SocketAddr::new(result.ip_address, result.port);
+            let country = result.country_code.as_deref().unwrap_or("??");
+            writeln!(self.writer, "{:<21} {:>6} {:<2} {}", addr, result.response_time_ms, country, result.protocol.scheme())?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Only addresses are written, never credentials, and every element is single-quoted
+/// so nothing in the output can expand when sourced.
+pub struct EnvSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> EnvSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for EnvSink<W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        writeln!(self.writer, "PROXIES=(")?;
+        for result in results {
+            writeln!(self.writer, "  '{}'", std::net::SocketAddr::new(result.ip_address, result.port))?;
+        }
+        writeln!(self.writer, ")")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `FindProxyForURL` rotates the proxy list by a hash of the host, so each site sticks to
+/// one proxy while sites overall are spread round-robin; the rest of the list, then
+/// `DIRECT`, serve as fallbacks. Credentials are left out, as PAC has no way to carry them.
+pub struct PacSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PacSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> OutputSink for PacSink<W> {
+    fn write_results(&mut self, results: &[ProxyResult]) -> Result<()> {
+        writeln!(self.writer, "var proxies = [")?;
+        for result in results {
+            let keyword = match result.protocol {
+                Protocol::Http => "PROXY",
+                Protocol::Socks5 => "SOCKS5",
+            };
+            writeln!(self.writer, "  \"{} {}\",", keyword, std::net::SocketAddr::new(result.ip_address, result.port))?;
+        }
+        writeln!(self.writer, "];")?;
+        writeln!(self.writer)?;
+        writeln!(self.writer, "function FindProxyForURL(url, host) {{")?;
+        writeln!(self.writer, "  if (proxies.length === 0) return \"DIRECT\";")?;
+        writeln!(self.writer, "  var hash = 0;")?;
+        writeln!(self.writer, "  for (var i = 0; i < host.length; i++) hash = (hash * 31 + host.charCodeAt(i)) % 2147483647;")?;
+        writeln!(self.writer, "  var start = hash % proxies.length;")?;
+        writeln!(self.writer, "  return proxies.slice(start).concat(proxies.slice(0, start), [\"DIRECT\"]).join(\"; \");")?;
+        writeln!(self.writer, "}}")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Double-quotes a YAML scalar so IPv6 colons and other punctuation stay literal.
+fn yaml_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,148 @@
+// src/ports.rs
+
+use crate::ScannerError;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A list of ports in Nmap syntax: `80,443,8000-8100,T:1080`.
+///
+/// Ranges are inclusive and may leave out either end (`-1024`, `60000-`). A `T:`
+/// prefix marks the ports after it as TCP, which is all this scanner speaks, so
+/// `U:`/`S:` sections are rejected rather than silently scanned over TCP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSpec(Vec<u16>);
+
+impl PortSpec {
+    /// The ports in the order given, with duplicates removed.
+    pub fn ports(&self) -> &[u16] {
+        &self.0
+    }
+
+    /// Joins repeated `--ports` specs, keeping each port's first position.
+    pub fn merge<'a>(specs: impl IntoIterator<Item = &'a PortSpec>) -> Self {
+        Self(dedup(specs.into_iter().flat_map(|spec| spec.0.iter().copied()).collect()))
+    }
+
+    /// Reads `--ports-from-file`: specs separated by commas or newlines, `#` starting a comment.
+    pub fn load(path: &Path) -> Result<Self, ScannerError> {
+        let text = fs::read_to_string(path).map_err(|source| ScannerError::Io { path: path.to_path_buf(), source })?;
+        let mut ports = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let spec = line
+                .parse::<PortSpec>()
+                .map_err(|e| ScannerError::InvalidPorts(format!("{} line {}: {}", path.display(), number + 1, e)))?;
+            ports.extend(spec.0);
+        }
+        if ports.is_empty() {
+            return Err(ScannerError::InvalidPorts(format!("No ports found in {}", path.display())));
+        }
+        Ok(Self(dedup(ports)))
+    }
+}
+
+impl FromStr for PortSpec {
+    type Err = ScannerError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut ports = Vec::new();
+        let mut column = 1;
+        for raw in spec.split(',') {
+            let token = raw.trim();
+            let at = column + (raw.len() - raw.trim_start().len());
+            column += raw.len() + 1;
+            if token.is_empty() {
+                return Err(ScannerError::InvalidPorts(format!("empty port entry at column {} in {:?}", at, spec)));
+            }
+            parse_token(token, &mut ports)
+                .map_err(|reason| ScannerError::InvalidPorts(format!("invalid port {:?} at column {}: {}", token, at, reason)))?;
+        }
+        Ok(Self(dedup(ports)))
+    }
+}
+
+fn parse_token(token: &str, ports: &mut Vec<u16>) -> Result<(), String> {
+    let token = match token.split_once(':') {
+        Some((prefix, rest)) if prefix.eq_ignore_ascii_case("t") => rest.trim(),
+        Some((prefix, _)) if prefix.eq_ignore_ascii_case("u") => return Err("UDP ports cannot be scanned".to_string()),
+        Some((prefix, _)) => return Err(format!("unknown protocol prefix {:?} (only T: is supported)", prefix)),
+        None => token,
+    };
+    match token.split_once('-') {
+        Some((start, end)) => {
+            let start = if start.trim().is_empty() { 1 } else { parse_port(start)? };
+            let end = if end.trim().is_empty() { u16::MAX } else { parse_port(end)? };
+            if start > end {
+                return Err(format!("range start {} is greater than its end {}", start, end));
+            }
+            ports.extend(start..=end);
+        }
+        None => ports.push(parse_port(token)?),
+    }
+    Ok(())
+}
+
+fn parse_port(value: &str) -> Result<u16, String> {
+    match value.trim().parse::<u16>() {
+        Ok(0) => Err("port 0 cannot be scanned".to_string()),
+        Ok(port) => Ok(port),
+        Err(_) => Err(format!("{:?} is not a port number between 1 and 65535", value.trim())),
+    }
+}
+
+fn dedup(ports: Vec<u16>) -> Vec<u16> {
+    let mut seen = std::collections::HashSet::new();
+    ports.into_iter().filter(|port| seen.insert(*port)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(spec: &str) -> Vec<u16> {
+        spec.parse::<PortSpec>().unwrap().ports().to_vec()
+    }
+
+    #[test]
+    fn parses_lists_ranges_and_tcp_prefix() {
+        assert_eq!(ports("80"), [80]);
+        assert_eq!(ports("80, 443,8000-8002"), [80, 443, 8000, 8001, 8002]);
+        assert_eq!(ports("T:1080,t:3128"), [1080, 3128]);
+    }
+
+    #[test]
+    fn keeps_first_occurrence_of_duplicates() {
+        assert_eq!(ports("443,80-81,443,80"), [443, 80, 81]);
+    }
+
+    #[test]
+    fn merges_repeated_specs_without_duplicates() {
+        let specs: Vec<PortSpec> = ["80,443", "8080,443", "80-81"].iter().map(|spec| spec.parse().unwrap()).collect();
+        assert_eq!(PortSpec::merge(&specs).ports(), [80, 443, 8080, 81]);
+    }
+
+    #[test]
+    fn accepts_the_edges_of_the_port_space() {
+        assert_eq!(ports("1"), [1]);
+        assert_eq!(ports("65535"), [65535]);
+        assert_eq!(ports("-3"), [1, 2, 3]);
+        assert_eq!(ports("65534-"), [65534, 65535]);
+        assert_eq!(ports("-").len(), 65535);
+    }
+
+    #[test]
+    fn rejects_bad_ports_with_their_column() {
+        let error = |spec: &str| spec.parse::<PortSpec>().unwrap_err().to_string();
+        assert_eq!(error("0"), r#"invalid port "0" at column 1: port 0 cannot be scanned"#);
+        assert_eq!(error("80,65536"), r#"invalid port "65536" at column 4: "65536" is not a port number between 1 and 65535"#);
+        assert_eq!(error("80, 90-10"), r#"invalid port "90-10" at column 5: range start 90 is greater than its end 10"#);
+        assert_eq!(error("80,,81"), r#"empty port entry at column 4 in "80,,81""#);
+        assert_eq!(error("U:53"), r#"invalid port "U:53" at column 1: UDP ports cannot be scanned"#);
+        assert_eq!(error("S:80"), r#"invalid port "S:80" at column 1: unknown protocol prefix "S" (only T: is supported)"#);
+        assert!(matches!("80,0".parse::<PortSpec>(), Err(ScannerError::InvalidPorts(_))));
+    }
+}
@@ -0,0 +1,697 @@
+// src/producer.rs
+
+use crate::events::Event;
+use crate::{log_event, log_verbose, Cli};
+use anyhow::{Context, Result};
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use indicatif::ProgressBar;
+use ipnet::IpNet;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, Read};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
+
+// --- Input Records and Filters ---
+
+/// The input-list column holding each entry; any other columns are ignored.
+const ADDRESS_COLUMN: &str = "IP Address";
+
+fn address_column(headers: &csv::StringRecord) -> Result<usize> {
+    headers.iter().position(|name| name.trim() == ADDRESS_COLUMN).with_context(|| format!("Input has no {:?} column", ADDRESS_COLUMN))
+}
+
+/// Basic-auth credentials for one proxy, taken from its input-list entry.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("username", &self.username).field("password", &"***").finish()
+    }
+}
+
+/// One address handed from the producer to the test stage.
+#[derive(Debug, Clone)]
+pub struct ProxyTarget {
+    pub addr: SocketAddr,
+    pub credentials: Option<Credentials>,
+    /// Tests already run against this target (non-zero on a `--second-pass` retry)
+    pub attempts: u32,
+    /// Why the most recent test failed
+    pub last_error: Option<String>,
+    /// What the service sent right after the scan connected (`--grab-banner`)
+    pub banner: Option<String>,
+    /// Test time spent on this target so far, counted against `--max-runtime-per-host`
+    pub elapsed: Duration,
+}
+
+impl ProxyTarget {
+    fn new(addr: SocketAddr, credentials: Option<Credentials>) -> Self {
+        Self { addr, credentials, attempts: 0, last_error: None, banner: None, elapsed: Duration::ZERO }
+    }
+}
+
+impl From<SocketAddr> for ProxyTarget {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr, None)
+    }
+}
+
+/// Parses an input-list entry: `ip`, `ip:port`, `user:pass@ip:port` or `ip:port:user:pass`.
+/// IPv6 hosts need brackets whenever a port is given, and entries without a port expand to
+/// one target per `--port`. Returns `None` for malformed entries, port 0 included.
+fn parse_proxy_entry(entry: &str, default_ports: &[u16]) -> Option<Vec<ProxyTarget>> {
+    let entry = entry.trim();
+    let parse_host = |host: &str| match host.parse::<SocketAddr>() {
+        Ok(addr) if addr.port() == 0 => None,
+        Ok(addr) => Some(vec![addr]),
+        Err(_) => host.parse::<IpAddr>().ok().map(|ip| default_ports.iter().map(|&port| SocketAddr::new(ip, port)).collect()),
+    };
+    let credentials = |username: &str, password: &str| {
+        (!username.is_empty()).then(|| Credentials { username: username.to_string(), password: password.to_string() })
+    };
+    let with_credentials = |addrs: Vec<SocketAddr>, credentials: Credentials| {
+        addrs.into_iter().map(|addr| ProxyTarget::new(addr, Some(credentials.clone()))).collect()
+    };
+
+    if let Some(addrs) = parse_host(entry) {
+        return Some(addrs.into_iter().map(ProxyTarget::from).collect());
+    }
+    if let Some((userinfo, host)) = entry.rsplit_once('@') {
+        let (username, password) = userinfo.split_once(':')?;
+        return Some(with_credentials(parse_host(host)?, credentials(username, password)?));
+    }
+    let mut parts = entry.rsplitn(3, ':');
+    let (password, username, host) = (parts.next()?, parts.next()?, parts.next()?);
+    let addr = host.parse::<SocketAddr>().ok().filter(|addr| addr.port() != 0)?;
+    Some(with_credentials(vec![addr], credentials(username, password)?))
+}
+
+/// Addresses loaded from `--skip-file` that the producers should not bother testing.
+#[derive(Debug, Default)]
+pub struct SkipList {
+    addrs: HashSet<SocketAddr>,
+    ips: HashSet<IpAddr>,
+    pub skipped: AtomicUsize,
+}
+
+impl SkipList {
+    /// Reads a plain list of addresses, one per line, or a CSV with a header. A results
+    /// file is read through its `IP Address` and `Port` columns, so each recorded ip:port
+    /// is skipped; entries without a port skip every port on that IP.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+
+    fn from_reader(reader: impl Read) -> Result<Self> {
+        let mut rdr = csv::ReaderBuilder::new().has_headers(false).flexible(true).trim(csv::Trim::All).from_reader(reader);
+        let mut list = Self::default();
+        // Plain lists have no header: the address is the first (only) field
+        let (mut ip_column, mut port_column) = (0, None);
+        for (number, record) in rdr.records().enumerate() {
+            let record = record?;
+            if number == 0
+                && let Some(column) = record.iter().position(|name| name == ADDRESS_COLUMN)
+            {
+                ip_column = column;
+                port_column = record.iter().position(|name| name == "Port");
+                continue;
+            }
+            let field = record.get(ip_column).unwrap_or("");
+            let port = port_column.and_then(|column| record.get(column)).and_then(|port| port.parse::<u16>().ok());
+            if let Ok(addr) = field.parse::<SocketAddr>() {
+                list.addrs.insert(addr);
+            } else if let Ok(ip) = field.parse::<IpAddr>() {
+                match port {
+                    Some(port) => list.addrs.insert(SocketAddr::new(ip, port)),
+                    None => list.ips.insert(ip),
+                };
+            }
+        }
+        Ok(list)
+    }
+
+    /// Returns true (and counts it) when `addr` is already known.
+    pub fn should_skip(&self, addr: &SocketAddr) -> bool {
+        let known = self.addrs.contains(addr) || self.ips.contains(&addr.ip());
+        if known {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        known
+    }
+}
+
+/// `--auto-throttle`: watches the share of probes that end in connection errors (resets,
+/// unreachable networks; not plain timeouts or refusals) and paces every scan thread
+/// with a growing delay while that share stays above the threshold, easing off again
+/// once it subsides.
+struct ScanThrottle {
+    /// Error percentage that triggers a slowdown
+    threshold: u32,
+    /// Probes and errors seen in the current window
+    window: Mutex<(u32, u32)>,
+    /// Pause before each probe, in microseconds
+    delay_us: AtomicU64,
+}
+
+impl ScanThrottle {
+    const WINDOW: u32 = 200;
+    const MIN_DELAY_US: u64 = 1_000;
+    const MAX_DELAY_US: u64 = 1_000_000;
+
+    fn new(threshold: u32) -> Self {
+        Self { threshold, window: Mutex::new((0, 0)), delay_us: AtomicU64::new(0) }
+    }
+
+    fn pace(&self) {
+        let delay = self.delay_us.load(Ordering::Relaxed);
+        if delay > 0 {
+            std::thread::sleep(Duration::from_micros(delay));
+        }
+    }
+
+    /// Counts one probe; at the end of each window, adjusts the delay and describes the change.
+    fn record(&self, error: bool) -> Option<String> {
+        let mut window = self.window.lock().unwrap();
+        let (probes, errors) = &mut *window;
+        *probes += 1;
+        *errors += u32::from(error);
+        if *probes < Self::WINDOW {
+            return None;
+        }
+        let percent = *errors * 100 / *probes;
+        *window = (0, 0);
+        let old = self.delay_us.load(Ordering::Relaxed);
+        let new = if percent > self.threshold {
+            (old * 2).clamp(Self::MIN_DELAY_US, Self::MAX_DELAY_US)
+        } else if percent <= self.threshold / 4 && old > 0 {
+            // Below the minimum the pause is dropped altogether
+            Some(old / 2).filter(|&delay| delay >= Self::MIN_DELAY_US).unwrap_or(0)
+        } else {
+            old
+        };
+        if new == old {
+            return None;
+        }
+        self.delay_us.store(new, Ordering::Relaxed);
+        Some(match new {
+            0 => format!("{}% connection errors, back to full speed", percent),
+            _ => format!("{}% connection errors, pausing {} ms before each probe", percent, new / 1_000),
+        })
+    }
+}
+
+/// Whether a probe failed for a reason that points at an overloaded path rather than the host
+fn is_connection_error<T>(connected: &io::Result<T>) -> bool {
+    connected.as_ref().is_err_and(|e| !matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused))
+}
+
+/// Caps how many lines per second the scan phase may print, so verbose mode
+/// doesn't flood the terminal on large subnets.
+struct LogRateLimiter {
+    state: Mutex<(Instant, u32, u64)>, // window start, lines printed, lines suppressed
+    per_second: u32,
+}
+
+impl LogRateLimiter {
+    fn new(per_second: u32) -> Self {
+        Self { state: Mutex::new((Instant::now(), 0, 0)), per_second }
+    }
+
+    fn log(&self, pb: &ProgressBar, cli: &Cli, msg: String) {
+        if !cli.verbose {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let (window_start, printed, suppressed) = &mut *state;
+        if window_start.elapsed() >= Duration::from_secs(1) {
+            if *suppressed > 0 {
+                log_verbose(pb, cli, format!("[{}]    ... {} more scan results suppressed", "SCAN".dimmed().bold(), suppressed));
+            }
+            *window_start = Instant::now();
+            *printed = 0;
+            *suppressed = 0;
+        }
+        if *printed < self.per_second {
+            *printed += 1;
+            log_verbose(pb, cli, msg);
+        } else {
+            *suppressed += 1;
+        }
+    }
+}
+
+/// Opens an input list, transparently decompressing it when it ends in `.gz`
+/// or `--gzip` is set.
+pub fn open_input(path: &Path, gzip: bool) -> Result<Box<dyn Read>> {
+    let file = std::fs::File::open(path)?;
+    if is_gzip(path, gzip) {
+        Ok(Box::new(GzDecoder::new(BufReader::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Whether `path` should be read or written as gzip: forced by a flag or implied by `.gz`.
+pub fn is_gzip(path: &Path, gzip: bool) -> bool {
+    gzip || path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+/// Reads `--subnet-file`: one CIDR block per line, blank lines and `#` comments ignored.
+/// Bad lines are reported and skipped on their own rather than failing the whole file.
+pub fn load_subnet_file(path: &Path, gzip: bool) -> Result<Vec<IpNet>> {
+    let mut contents = String::new();
+    open_input(path, gzip)?.read_to_string(&mut contents)?;
+
+    let mut networks = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse::<IpNet>() {
+            Ok(network) => networks.push(network),
+            Err(e) => eprintln!("[{}] {}:{}: skipping {:?}: {}", "WARN".yellow().bold(), path.display(), number + 1, line, e),
+        }
+    }
+    if networks.is_empty() {
+        anyhow::bail!("No valid CIDR blocks in {}", path.display());
+    }
+    Ok(networks)
+}
+
+/// How long `--grab-banner` waits for a service to speak first.
+const BANNER_WAIT: Duration = Duration::from_millis(500);
+
+/// Reads whatever a service volunteers right after the connect, up to `limit` bytes, with
+/// control characters escaped. HTTP and SOCKS proxies usually stay silent.
+fn read_banner(mut stream: TcpStream, limit: usize) -> Option<String> {
+    stream.set_read_timeout(Some(BANNER_WAIT)).ok()?;
+    let mut buf = vec![0; limit];
+    let n = stream.read(&mut buf).ok()?;
+    let banner = String::from_utf8_lossy(&buf[..n]).trim().escape_debug().to_string();
+    (!banner.is_empty()).then_some(banner)
+}
+
+// --- Adaptive Sweep ---
+
+/// `--adaptive-sweep` first probes every this-many-th host of each block.
+const SWEEP_STRIDE: u8 = 16;
+
+/// The block a host is judged by during `--adaptive-sweep`: its /24, or the /120 (also 256
+/// addresses) for IPv6.
+fn sweep_block(ip: &IpAddr) -> IpNet {
+    let prefix_len = match ip {
+        IpAddr::V4(_) => 24,
+        IpAddr::V6(_) => 120,
+    };
+    IpNet::new(*ip, prefix_len).expect("prefix length fits the address family").trunc()
+}
+
+/// Whether `ip` is one of the coarse-pass hosts: .1, .17, .33 ... of its block.
+fn is_sweep_sample(ip: &IpAddr) -> bool {
+    let last = match ip {
+        IpAddr::V4(v4) => v4.octets()[3],
+        IpAddr::V6(v6) => v6.octets()[15],
+    };
+    last % SWEEP_STRIDE == 1
+}
+
+/// Addresses that are pointless or unroutable scan targets: loopback, multicast, broadcast,
+/// unspecified and link-local. `global_only` additionally drops private IPv4 and
+/// unique-local IPv6 space.
+fn is_special(ip: &IpAddr, global_only: bool) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_multicast()
+                || v4.is_broadcast()
+                || v4.is_unspecified()
+                || v4.is_link_local()
+                || (global_only && v4.is_private())
+        }
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_multicast()
+                || v6.is_unspecified()
+                || v6.is_unicast_link_local()
+                || (global_only && v6.is_unique_local())
+        }
+    }
+}
+
+// --- Producer (Scanner or File Reader) ---
+
+/// Feeds candidate addresses to the test stage from a blocking thread.
+pub struct Producer {
+    pub cli: Cli,
+    pub pb: ProgressBar,
+    pub skip: Arc<SkipList>,
+    /// Set by the consumer to end production early (e.g. once `--limit` is reached)
+    pub stop: Arc<AtomicBool>,
+    /// Networks from `--subnet` or `--subnet-file`
+    pub networks: Vec<IpNet>,
+    /// Addresses resolved from `--target`
+    pub targets: Vec<IpAddr>,
+    /// Drives `--shuffle-ports`; seeded from `--seed` when given
+    pub rng: StdRng,
+    pub tx: mpsc::Sender<ProxyTarget>,
+}
+
+impl Producer {
+    pub fn run(self) -> Result<()> {
+        if !self.networks.is_empty() {
+            self.scan_and_send()
+        } else if let Some(path) = &self.cli.source.input {
+            self.read_and_send(path).with_context(|| format!("Failed to read {}", path.display()))
+        } else {
+            self.send_targets();
+            Ok(())
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn scan_and_send(&self) -> Result<()> {
+        let cli = &self.cli;
+        // Listed blocks may overlap; scan each host once
+        let mut seen = HashSet::new();
+        let mut hosts_to_scan: Vec<IpAddr> = self.networks.iter().flat_map(IpNet::hosts).filter(|ip| seen.insert(*ip)).collect();
+        if !cli.include_special {
+            for ip in cli.source.ip.iter().filter(|ip| is_special(ip, cli.global_only)) {
+                log_verbose(&self.pb, cli, format!("[{}]    Scanning {} anyway, as it was given with --ip", "SCAN".dimmed().bold(), ip));
+            }
+            let before = hosts_to_scan.len();
+            // Addresses named with --ip are scanned as asked; only those from ranges are filtered
+            hosts_to_scan.retain(|ip| cli.source.ip.contains(ip) || !is_special(ip, cli.global_only));
+            let dropped = before - hosts_to_scan.len();
+            if dropped > 0 {
+                log_verbose(&self.pb, cli, format!("[{}]    Skipping {} special-use addresses (use --include-special to scan them)", "SCAN".dimmed().bold(), dropped));
+            }
+        }
+        let host_count = hosts_to_scan.len();
+        // Host-major order by default; --shuffle-ports spreads probes across both hosts and ports
+        let mut probes: Vec<SocketAddr> = hosts_to_scan
+            .into_iter()
+            .flat_map(|ip| cli.ports.iter().map(move |&port| SocketAddr::new(ip, port)))
+            .collect();
+        if cli.shuffle_ports {
+            probes.shuffle(&mut self.rng.clone());
+        }
+        let limiter = LogRateLimiter::new(20);
+        let throttle = cli.auto_throttle.map(ScanThrottle::new);
+        let enqueued = AtomicUsize::new(0);
+        // Rayon threads aren't runtime threads, so --scan-via probes are driven through a handle
+        let runtime = tokio::runtime::Handle::current();
+        let probe = |addr: SocketAddr| -> bool {
+            if self.stopped() || self.skip.should_skip(&addr) {
+                return false;
+            }
+            if let Some(throttle) = &throttle {
+                throttle.pace();
+            }
+            let timeout = cli.scan_timeout;
+            let connected = match &cli.scan_via {
+                Some(via) => runtime.block_on(via.probe(addr, timeout)).map(|()| None),
+                None => TcpStream::connect_timeout(&cli.nat64_prefix.map_or(addr, |prefix| prefix.synthesize(addr)), timeout).map(|stream| cli.grab_banner.and_then(|limit| read_banner(stream, limit))),
+            };
+            if let Some(message) = throttle.as_ref().and_then(|throttle| throttle.record(is_connection_error(&connected))) {
+                log_verbose(&self.pb, cli, format!("[{}]    {}", "SCAN".dimmed().bold(), message));
+            }
+            let (open, outcome) = match connected {
+                Ok(banner) => {
+                    log_event(cli, Event::HostOpen { address: addr, banner: banner.as_deref() });
+                    enqueued.fetch_add(1, Ordering::Relaxed);
+                    let outcome = match &banner {
+                        Some(banner) => format!("{} {}", "open".green(), banner.dimmed()),
+                        None => "open".green().to_string(),
+                    };
+                    let _ = self.tx.blocking_send(ProxyTarget { banner, ..addr.into() });
+                    (true, outcome)
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (false, "timeout".yellow().to_string()),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => (false, "closed".red().to_string()),
+                Err(e) => (false, e.to_string()),
+            };
+            limiter.log(&self.pb, cli, format!("[{}]    {} {}", "SCAN".dimmed().bold(), addr, outcome));
+            open
+        };
+        // Probes every address and returns the ones that were open.
+        // with_max_len(1) keeps rayon from handing each thread a contiguous (and so ordered) chunk
+        let scan_all = |probes: Vec<SocketAddr>| -> Vec<SocketAddr> { probes.into_par_iter().with_max_len(1).filter(|&addr| probe(addr)).collect() };
+        let scan = || -> usize {
+            let Some(min_open) = cli.adaptive_sweep else {
+                scan_all(probes);
+                return host_count;
+            };
+            // Coarse pass over a few hosts per /24, then a full pass over the blocks that look busy
+            let (sample, mut rest): (Vec<_>, Vec<_>) = probes.into_iter().partition(|addr| is_sweep_sample(&addr.ip()));
+            let mut open_per_block: HashMap<IpNet, usize> = HashMap::new();
+            for addr in scan_all(sample.clone()) {
+                *open_per_block.entry(sweep_block(&addr.ip())).or_default() += 1;
+            }
+            let total_blocks = sample.iter().chain(&rest).map(|addr| sweep_block(&addr.ip())).collect::<HashSet<_>>().len();
+            let busy_blocks: HashSet<IpNet> = open_per_block.into_iter().filter(|&(_, open)| open >= min_open).map(|(block, _)| block).collect();
+            rest.retain(|addr| busy_blocks.contains(&sweep_block(&addr.ip())));
+            log_verbose(&self.pb, cli, format!("[{}]    Adaptive sweep: {} of {} blocks reached {} open in the sample, scanning them fully", "SCAN".dimmed().bold(), busy_blocks.len(), total_blocks, min_open));
+            let scanned = sample.iter().chain(&rest).map(SocketAddr::ip).collect::<HashSet<_>>().len();
+            scan_all(rest);
+            scanned
+        };
+
+        // A dedicated pool lets IO-bound scans oversubscribe the CPU count
+        let host_count = match cli.scan_threads {
+            Some(n) => rayon::ThreadPoolBuilder::new().num_threads(n).build()?.install(scan),
+            None => scan(),
+        };
+
+        // Every open port is now known, so the test phase has a real total
+        let enqueued = enqueued.into_inner() as u64;
+        self.pb.set_style(crate::bar_style()?);
+        self.pb.set_length(enqueued);
+        self.pb.set_message(format!("scanned {} hosts, testing {} open", host_count, enqueued));
+        Ok(())
+    }
+
+    fn send_targets(&self) {
+        let addrs = self.targets.iter().flat_map(|&ip| self.cli.ports.iter().map(move |&port| SocketAddr::new(ip, port)));
+        for addr in addrs {
+            if self.stopped() {
+                break;
+            }
+            if self.skip.should_skip(&addr) {
+                self.pb.inc(1);
+                continue;
+            }
+            let _ = self.tx.blocking_send(addr.into());
+        }
+    }
+
+    fn read_and_send(&self, path: &Path) -> Result<()> {
+        // Short rows are reported below as bad rows instead of failing the reader itself
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(open_input(path, self.cli.gzip)?);
+        let column = address_column(rdr.headers()?)?;
+        let limiter = LogRateLimiter::new(20);
+        for result in rdr.records() {
+            if self.stopped() {
+                break;
+            }
+            let raw = match result {
+                Ok(raw) => raw,
+                Err(e) => {
+                    let line = e.position().map_or(0, |position| position.line());
+                    self.bad_row(&limiter, line, "", e)?;
+                    continue;
+                }
+            };
+            let Some(entry) = raw.get(column) else {
+                let line = raw.position().map_or(0, |position| position.line());
+                self.bad_row(&limiter, line, &raw.iter().collect::<Vec<_>>().join(","), format!("no {:?} field", ADDRESS_COLUMN))?;
+                continue;
+            };
+            for target in self.accept_entry(entry, &limiter) {
+                let _ = self.tx.blocking_send(target);
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips a row that doesn't parse, or with `--strict-input` fails the read on it.
+    fn bad_row(&self, limiter: &LogRateLimiter, line: u64, raw: &str, error: impl std::fmt::Display) -> Result<()> {
+        if self.cli.strict_input {
+            anyhow::bail!("Malformed input row on line {} {:?}: {}", line, raw, error);
+        }
+        limiter.log(&self.pb, &self.cli, format!("[{}]   Skipping malformed row on line {} {:?}: {}", "INPUT".yellow().bold(), line, raw, error));
+        Ok(())
+    }
+
+    /// Whether `run_async` can do this producer's work (`--async-input` on a plain file).
+    pub fn reads_async(&self) -> bool {
+        self.cli.async_input && self.networks.is_empty() && self.cli.source.input.as_deref().is_some_and(|path| !is_gzip(path, self.cli.gzip))
+    }
+
+    /// The `--async-input` counterpart of `run`, streaming the input file on the runtime.
+    pub async fn run_async(self) -> Result<()> {
+        match &self.cli.source.input {
+            Some(path) => self.read_and_send_async(path).await.with_context(|| format!("Failed to read {}", path.display())),
+            None => Ok(()),
+        }
+    }
+
+    async fn read_and_send_async(&self, path: &Path) -> Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let mut lines = tokio::io::BufReader::new(file).lines();
+        let Some(header) = lines.next_line().await? else {
+            return Ok(());
+        };
+        let column = address_column(&parse_csv_line(&header)?)?;
+        let limiter = LogRateLimiter::new(20);
+        let mut number = 1;
+        while let Some(line) = lines.next_line().await? {
+            number += 1;
+            if self.stopped() {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let raw = match parse_csv_line(&line) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    self.bad_row(&limiter, number, &line, e)?;
+                    continue;
+                }
+            };
+            let Some(entry) = raw.get(column) else {
+                self.bad_row(&limiter, number, &line, format!("no {:?} field", ADDRESS_COLUMN))?;
+                continue;
+            };
+            for target in self.accept_entry(entry, &limiter) {
+                if self.tx.send(target).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands one input-list entry into the targets worth queueing, growing the bar to match.
+    fn accept_entry(&self, entry: &str, limiter: &LogRateLimiter) -> Vec<ProxyTarget> {
+        let Some(mut targets) = parse_proxy_entry(entry, &self.cli.ports) else {
+            limiter.log(&self.pb, &self.cli, format!("[{}]   Skipping malformed entry {:?}", "INPUT".yellow().bold(), entry));
+            return Vec::new();
+        };
+        targets.retain(|target| self.cli.allows_family(&target.addr.ip()) && !self.skip.should_skip(&target.addr));
+        // The file is only read once, so the bar's total grows as rows are queued
+        self.pb.inc_length(targets.len() as u64);
+        targets
+    }
+}
+
+/// Splits a single CSV line into fields, honouring quotes.
+fn parse_csv_line(line: &str) -> csv::Result<csv::StringRecord> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(line.as_bytes());
+    Ok(rdr.records().next().transpose()?.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each target an entry expands to, as its address and any `(username, password)`
+    type Expanded = Option<Vec<(String, Option<(String, String)>)>>;
+
+    fn entry(spec: &str) -> Expanded {
+        let targets = parse_proxy_entry(spec, &[8080, 3128])?;
+        Some(targets.into_iter().map(|t| (t.addr.to_string(), t.credentials.map(|c| (c.username, c.password)))).collect())
+    }
+
+    fn plain(addrs: &[&str]) -> Expanded {
+        Some(addrs.iter().map(|addr| (addr.to_string(), None)).collect())
+    }
+
+    fn with_login(addr: &str, username: &str, password: &str) -> Expanded {
+        Some(vec![(addr.to_string(), Some((username.to_string(), password.to_string())))])
+    }
+
+    #[test]
+    fn bare_hosts_expand_to_every_default_port() {
+        assert_eq!(entry("1.2.3.4"), plain(&["1.2.3.4:8080", "1.2.3.4:3128"]));
+        assert_eq!(entry("  2001:db8::1 "), plain(&["[2001:db8::1]:8080", "[2001:db8::1]:3128"]));
+    }
+
+    #[test]
+    fn explicit_ports_are_kept() {
+        assert_eq!(entry("1.2.3.4:80"), plain(&["1.2.3.4:80"]));
+        assert_eq!(entry("[2001:db8::1]:1080"), plain(&["[2001:db8::1]:1080"]));
+        assert_eq!(entry("1.2.3.4:65535"), plain(&["1.2.3.4:65535"]));
+    }
+
+    #[test]
+    fn credentials_in_either_layout() {
+        assert_eq!(entry("user:pass@1.2.3.4:80"), with_login("1.2.3.4:80", "user", "pass"));
+        assert_eq!(entry("1.2.3.4:80:user:pass"), with_login("1.2.3.4:80", "user", "pass"));
+        assert_eq!(entry("[::1]:80:user:pass"), with_login("[::1]:80", "user", "pass"));
+        // Only the last `@` separates the host, and only the first `:` the password
+        assert_eq!(entry("user:p@ss:word@1.2.3.4:80"), with_login("1.2.3.4:80", "user", "p@ss:word"));
+        assert_eq!(entry("user:@1.2.3.4:80"), with_login("1.2.3.4:80", "user", ""));
+    }
+
+    #[test]
+    fn skip_list_reads_results_csv_ports() {
+        let csv = "IP Address,Port,Protocol\n1.2.3.4,8080,http\n\"5.6.7.8\",,socks5\n";
+        let list = SkipList::from_reader(csv.as_bytes()).unwrap();
+        assert!(list.should_skip(&"1.2.3.4:8080".parse().unwrap()));
+        assert!(!list.should_skip(&"1.2.3.4:3128".parse().unwrap()));
+        // No port recorded, so the whole IP is skipped
+        assert!(list.should_skip(&"5.6.7.8:1".parse().unwrap()));
+    }
+
+    #[test]
+    fn skip_list_reads_plain_lists() {
+        let list = SkipList::from_reader("1.2.3.4:80\n# comment\n5.6.7.8\n".as_bytes()).unwrap();
+        assert!(list.should_skip(&"1.2.3.4:80".parse().unwrap()));
+        assert!(!list.should_skip(&"1.2.3.4:81".parse().unwrap()));
+        assert!(list.should_skip(&"5.6.7.8:3128".parse().unwrap()));
+        assert_eq!(list.skipped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        for spec in ["", "example.com:80", "1.2.3.4:0", "1.2.3.4:0:user:pass", "1.2.3.4:65536", "1.2.3", "user@1.2.3.4:80", ":pass@1.2.3.4:80", "1.2.3.4:80:user", "1.2.3.4::pass"] {
+            assert_eq!(entry(spec), None, "{:?} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn special_addresses_match_the_include_special_help() {
+        for ip in ["127.0.0.1", "224.0.0.1", "255.255.255.255", "0.0.0.0", "169.254.10.20", "::1", "ff02::1", "::", "fe80::1"] {
+            assert!(is_special(&ip.parse().unwrap(), false), "{} should be special", ip);
+        }
+        for ip in ["8.8.8.8", "10.0.0.1", "192.168.1.1", "2001:db8::1", "fd00::1"] {
+            assert!(!is_special(&ip.parse().unwrap(), false), "{} should be scanned by default", ip);
+        }
+    }
+
+    #[test]
+    fn global_only_also_drops_private_space() {
+        for ip in ["10.0.0.1", "172.16.0.1", "192.168.1.1", "fd00::1"] {
+            assert!(is_special(&ip.parse().unwrap(), true), "{} should be dropped by --global-only", ip);
+        }
+        assert!(!is_special(&"8.8.8.8".parse().unwrap(), true));
+    }
+}
@@ -0,0 +1,460 @@
+// src/rawproxy.rs
+
+use crate::producer::Credentials;
+use crate::Protocol;
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::TlsConnector;
+use tokio_socks::tcp::Socks5Stream;
+
+/// An inclusive range of local ports outgoing test connections must come from
+/// (`--local-port-range 40000-40999`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (start, end) = value.split_once('-').ok_or("expected START-END, for example 40000-40999")?;
+        let start: u16 = start.trim().parse().map_err(|e| format!("invalid start port: {}", e))?;
+        let end: u16 = end.trim().parse().map_err(|e| format!("invalid end port: {}", e))?;
+        if start == 0 || start > end {
+            return Err(format!("{}-{} is not a valid port range", start, end));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Rotates the first port tried so concurrent tests don't all fight over `start`.
+static NEXT_PORT: AtomicU32 = AtomicU32::new(0);
+
+/// Connects to `remote` from the first free local port in `range`.
+pub async fn connect_from_range(remote: SocketAddr, range: PortRange) -> Result<TcpStream> {
+    let len = u32::from(range.end - range.start) + 1;
+    let offset = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    for step in 0..len {
+        let port = range.start + ((offset + step) % len) as u16;
+        let socket = if remote.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.set_reuseaddr(true)?;
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], port)),
+        };
+        match socket.bind(local) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+        match socket.connect(remote).await {
+            Ok(stream) => return Ok(stream),
+            // Same local port already talks to this remote; try the next one
+            Err(e) if matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    bail!("No free local port in {}-{}", range.start, range.end)
+}
+
+/// A response read by `get`.
+pub struct RawResponse {
+    pub status: u16,
+    /// Time until the response headers arrived
+    pub ttfb: Duration,
+    /// Response headers in the order received, names as sent
+    pub headers: Vec<(String, String)>,
+    /// `None` when the body did not arrive within the `body_timeout` given to `request`
+    pub body: Option<Vec<u8>>,
+}
+
+/// reqwest can't pin the local port, so `--local-port-range` tests speak HTTP/1.1 to the
+/// proxy by hand over a socket bound in the range. Only plain-HTTP URLs are supported.
+/// With a `body_timeout` (`--geo-timeout`) the body gets its own deadline once the
+/// headers are in, as on the reqwest path.
+#[allow(clippy::too_many_arguments)]
+pub async fn request(
+    proxy: SocketAddr,
+    protocol: Protocol,
+    credentials: Option<&Credentials>,
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    range: PortRange,
+    body_timeout: Option<Duration>,
+) -> Result<RawResponse> {
+    let url = reqwest::Url::parse(url)?;
+    if url.scheme() != "http" {
+        bail!("--local-port-range only supports http:// test URLs");
+    }
+    let host = url.host_str().context("Test URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    // A `Host` in `headers` replaces the one taken from the URL
+    let is_host = |name: &str| name.eq_ignore_ascii_case("host");
+    let host_header = headers.iter().find(|(name, _)| is_host(name)).map_or(host, |(_, value)| value.as_str());
+    let mut extra: String = headers
+        .iter()
+        .filter(|(name, _)| !is_host(name))
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    if !matches!(method, "GET" | "HEAD") {
+        extra.push_str("Content-Length: 0\r\n");
+    }
+    let start = Instant::now();
+    let stream = connect_from_range(proxy, range).await?;
+
+    match protocol {
+        Protocol::Http => {
+            // Proxies expect the absolute URL in the request line
+            let auth = proxy_authorization(credentials);
+            let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n{}{}Connection: close\r\n\r\n", method, url, host_header, auth, extra);
+            exchange(stream, request, start, body_timeout).await
+        }
+        Protocol::Socks5 => {
+            let tunnel = match credentials {
+                Some(c) => Socks5Stream::connect_with_password_and_socket(stream, (host, port), &c.username, &c.password).await?,
+                None => Socks5Stream::connect_with_socket(stream, (host, port)).await?,
+            };
+            let path = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n", method, path, host_header, extra);
+            exchange(tunnel, request, start, body_timeout).await
+        }
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: String, start: Instant, body_timeout: Option<Duration>) -> Result<RawResponse> {
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 4096];
+    let split = loop {
+        if let Some(split) = raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            break split;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Proxy closed the connection before sending a full response");
+        }
+        raw.extend_from_slice(&buf[..n]);
+    };
+    let ttfb = start.elapsed();
+    let mut body = raw.split_off(split + 4);
+    let head = String::from_utf8_lossy(&raw[..split]).into_owned();
+
+    // The body has its own deadline, if any, from the moment the headers are in
+    let stalled = match body_timeout {
+        Some(limit) => match tokio::time::timeout(limit, stream.read_to_end(&mut body)).await {
+            Ok(read) => read.map(|_| false)?,
+            Err(_) => true,
+        },
+        None => stream.read_to_end(&mut body).await.map(|_| false)?,
+    };
+
+    let status = parse_status(&head)?;
+    let headers: Vec<(String, String)> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    if stalled {
+        return Ok(RawResponse { status, ttfb, headers, body: None });
+    }
+    let header = |name: &str| headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.clone());
+    // HEAD responses announce a (chunked) body they never send
+    if !body.is_empty() && header("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        body = dechunk(&body)?;
+    } else if let Some(len) = header("Content-Length").and_then(|len| len.parse::<usize>().ok()) {
+        body.truncate(len);
+    }
+
+    Ok(RawResponse { status, ttfb, headers, body: Some(body) })
+}
+
+/// `--connect-only`: opens a tunnel to `target` through the proxy (HTTP CONNECT or a
+/// SOCKS5 CONNECT) and returns how long the handshake took. Nothing is sent through it.
+pub async fn connect_handshake(
+    proxy: SocketAddr,
+    protocol: Protocol,
+    credentials: Option<&Credentials>,
+    target: &TunnelTarget,
+    range: Option<PortRange>,
+) -> Result<Duration> {
+    let start = Instant::now();
+    // Nothing is sent through the tunnel; it is dropped right away
+    drop(open_tunnel(proxy, protocol, credentials, &target.host, target.port, range).await?);
+    Ok(start.elapsed())
+}
+
+/// Asks the proxy for a tunnel to `host:port` (HTTP CONNECT or SOCKS5 CONNECT) and returns
+/// the socket, now carrying the tunnelled connection.
+async fn open_tunnel(
+    proxy: SocketAddr,
+    protocol: Protocol,
+    credentials: Option<&Credentials>,
+    host: &str,
+    port: u16,
+    range: Option<PortRange>,
+) -> Result<TcpStream> {
+    let mut stream = match range {
+        Some(range) => connect_from_range(proxy, range).await?,
+        None => TcpStream::connect(proxy).await?,
+    };
+
+    match protocol {
+        Protocol::Http => {
+            let auth = proxy_authorization(credentials);
+            let request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n{auth}\r\n");
+            stream.write_all(request.as_bytes()).await?;
+
+            // Read the response head byte by byte so nothing past it is consumed
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                if head.len() > 8192 || stream.read(&mut byte).await? == 0 {
+                    bail!("Proxy closed the connection during CONNECT");
+                }
+                head.push(byte[0]);
+            }
+            let status = parse_status(&String::from_utf8_lossy(&head))?;
+            if !(200..300).contains(&status) {
+                bail!("CONNECT refused with HTTP status {}", status);
+            }
+            Ok(stream)
+        }
+        Protocol::Socks5 => {
+            let tunnel = match credentials {
+                Some(c) => Socks5Stream::connect_with_password_and_socket(stream, (host, port), &c.username, &c.password).await?,
+                None => Socks5Stream::connect_with_socket(stream, (host, port)).await?,
+            };
+            Ok(tunnel.into_inner())
+        }
+    }
+}
+
+/// Where `--sni` or `--connect-only` tunnels to: `HOST:PORT`, with IPv6 hosts in brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelTarget {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for TunnelTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (host, port) = value.rsplit_once(':').ok_or("expected HOST:PORT, for example 203.0.113.7:443")?;
+        let port: u16 = port.parse().map_err(|e| format!("invalid port: {}", e))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("missing host".to_string());
+        }
+        Ok(Self { host: host.to_string(), port })
+    }
+}
+
+/// `--sni`: the name to present, where to tunnel to, and the TLS client to do it with.
+#[derive(Clone)]
+pub struct SniCheck {
+    name: ServerName<'static>,
+    target: TunnelTarget,
+    connector: TlsConnector,
+}
+
+impl std::fmt::Debug for SniCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SniCheck").field("name", &self.name).field("target", &self.target).finish_non_exhaustive()
+    }
+}
+
+impl SniCheck {
+    /// Trusts the system's roots, or no certificate checks at all with `--insecure`.
+    /// The tunnel goes to `target`, or to `host` on 443 when none is given.
+    pub fn new(host: &str, target: Option<TunnelTarget>, insecure: bool) -> Result<Self> {
+        let name = ServerName::try_from(host.to_string()).with_context(|| format!("Invalid --sni host name {:?}", host))?;
+        let target = target.unwrap_or_else(|| TunnelTarget { host: host.to_string(), port: 443 });
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+        let config = if insecure {
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider))).with_no_client_auth()
+        } else {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+        Ok(Self { name, target, connector: TlsConnector::from(Arc::new(config)) })
+    }
+
+    /// Tunnels to the target through the proxy and completes a TLS handshake that names
+    /// the SNI host in the ClientHello, whatever host the tunnel actually reaches. That is
+    /// how a fronted or censored name is tried against a bare IP.
+    pub async fn run(&self, proxy: SocketAddr, protocol: Protocol, credentials: Option<&Credentials>, range: Option<PortRange>) -> Result<()> {
+        let tunnel = open_tunnel(proxy, protocol, credentials, &self.target.host, self.target.port, range).await?;
+        self.connector.connect(self.name.clone(), tunnel).await?;
+        Ok(())
+    }
+}
+
+/// `--insecure`: accepts any certificate, but still checks the handshake signatures so
+/// only a broken handshake, not an untrusted name, fails the check.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// An upstream SOCKS5 proxy the scan phase connects through (`--scan-via socks5://[user:pass@]host:port`),
+/// so an "open" port means reachable from the proxy rather than from this machine.
+#[derive(Debug, Clone)]
+pub struct ScanVia {
+    proxy: SocketAddr,
+    credentials: Option<Credentials>,
+}
+
+impl FromStr for ScanVia {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let url = reqwest::Url::parse(value).map_err(|e| e.to_string())?;
+        if !matches!(url.scheme(), "socks5" | "socks5h") {
+            return Err(format!("unsupported scheme {:?}, expected socks5://host:port", url.scheme()));
+        }
+        let host = url.host_str().ok_or("missing proxy host")?;
+        let port = url.port().unwrap_or(1080);
+        let proxy = (host.trim_matches(|c| c == '[' || c == ']'), port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("{} did not resolve to an address", host))?;
+        let credentials = (!url.username().is_empty()).then(|| Credentials {
+            username: url.username().to_string(),
+            password: url.password().unwrap_or_default().to_string(),
+        });
+        Ok(Self { proxy, credentials })
+    }
+}
+
+impl ScanVia {
+    /// Asks the proxy to connect to `target`. Errors use the same kinds a direct connect
+    /// would (`TimedOut`, `ConnectionRefused`), so callers can classify both alike.
+    pub async fn probe(&self, target: SocketAddr, timeout: Duration) -> io::Result<()> {
+        let connect = async {
+            match &self.credentials {
+                Some(c) => Socks5Stream::connect_with_password(self.proxy, target, &c.username, &c.password).await,
+                None => Socks5Stream::connect(self.proxy, target).await,
+            }
+        };
+        match tokio::time::timeout(timeout, connect).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(tokio_socks::Error::ConnectionRefused)) => Err(io::ErrorKind::ConnectionRefused.into()),
+            Ok(Err(tokio_socks::Error::TtlExpired)) | Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            Ok(Err(e)) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+/// The `Proxy-Authorization` header line for basic auth, or nothing without credentials.
+fn proxy_authorization(credentials: Option<&Credentials>) -> String {
+    match credentials {
+        Some(credentials) => {
+            let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", credentials.username, credentials.password));
+            format!("Proxy-Authorization: Basic {}\r\n", token)
+        }
+        None => String::new(),
+    }
+}
+
+fn parse_status(head: &str) -> Result<u16> {
+    head.lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .context("Malformed HTTP status line")
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, ignoring chunk extensions and trailers.
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").context("Truncated chunk header")?;
+        let size_str = String::from_utf8_lossy(&data[..line_end]);
+        let size = usize::from_str_radix(size_str.split(';').next().unwrap_or("").trim(), 16).context("Malformed chunk size")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size {
+            bail!("Truncated chunk body");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = data.get(size + 2..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dechunk_joins_chunks() {
+        assert_eq!(dechunk(b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n").unwrap(), b"Wikipedia");
+        assert_eq!(dechunk(b"A\r\n0123456789\r\n0\r\n\r\n").unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn dechunk_ignores_extensions_and_trailers() {
+        assert_eq!(dechunk(b"3;name=value\r\nabc\r\n0\r\nExpires: never\r\n\r\n").unwrap(), b"abc");
+    }
+
+    #[test]
+    fn dechunk_accepts_an_empty_body() {
+        assert_eq!(dechunk(b"0\r\n\r\n").unwrap(), b"");
+    }
+
+    #[test]
+    fn dechunk_reports_malformed_input() {
+        let error = |data: &[u8]| dechunk(data).unwrap_err().to_string();
+        assert_eq!(error(b""), "Truncated chunk header");
+        assert_eq!(error(b"3\r\nabc\r\n"), "Truncated chunk header");
+        assert_eq!(error(b"zz\r\nabc\r\n0\r\n\r\n"), "Malformed chunk size");
+        assert_eq!(error(b"10\r\nshort\r\n0\r\n\r\n"), "Truncated chunk body");
+    }
+}
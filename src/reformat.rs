@@ -0,0 +1,92 @@
+// src/reformat.rs
+
+use crate::output::{self, OutputFormat, OutputOptions, TableStyle};
+use crate::{is_stdout, latency_rejection, producer, quality_score, write_results_to_file, ProxyResult, RankBy, Ranking, ResultFilters};
+use anyhow::{Context, Result};
+use std::io;
+use std::path::PathBuf;
+
+/// Re-filter, re-rank and re-format a saved results file without scanning again
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReformatArgs {
+    /// A results file from an earlier run: CSV with every column, or `--format json` output
+    /// (`.gz` is decompressed)
+    #[arg(value_name = "RESULTS")]
+    results: PathBuf,
+
+    #[command(flatten)]
+    filters: ResultFilters,
+
+    /// Only keep proxies located in these countries (ISO codes, e.g. `US,DE`)
+    #[arg(long, value_delimiter = ',', value_name = "CODES")]
+    country: Vec<String>,
+
+    #[command(flatten)]
+    ranking: Ranking,
+
+    /// Write the results to this file, or `-` for stdout; repeatable, format as for a scan
+    #[arg(long, short, value_name = "FILE_PATH")]
+    output: Vec<PathBuf>,
+
+    /// Format of the results (defaults to a table on screen; for --output, by file extension)
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Border style of the results table
+    #[arg(long, value_enum, default_value_t = TableStyle::Utf8)]
+    table_style: TableStyle,
+
+    /// Only write these CSV columns, in this order (e.g. `ip,port,latency,country`)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    csv_fields: Option<Vec<output::CsvField>>,
+}
+
+pub fn run(args: &ReformatArgs) -> Result<()> {
+    let path = &args.results;
+    let reader = producer::open_input(path, false).with_context(|| format!("Failed to open {}", path.display()))?;
+    let (scan, mut results) = match OutputFormat::from_extension(path) {
+        Some(OutputFormat::Json) => output::read_json_results(reader),
+        _ => output::read_csv_results(reader).map(|results| (Default::default(), results)),
+    }
+    .with_context(|| format!("Failed to read results from {}", path.display()))?;
+    let loaded = results.len();
+
+    results.retain(|result| keep(args, result));
+    results.sort_by_key(|result| result.response_time_ms);
+    if args.ranking.rank_by == RankBy::Score {
+        results.sort_by(|a, b| quality_score(&args.ranking, a).total_cmp(&quality_score(&args.ranking, b)));
+    }
+    eprintln!("Kept {} of {} results from {}", results.len(), loaded, path.display());
+
+    let options = OutputOptions {
+        timings: results.iter().any(|result| result.ttfb_ms.is_some()),
+        table_style: args.table_style,
+        group_by: None,
+        csv_fields: args.csv_fields.clone(),
+        scan,
+    };
+    if args.output.is_empty() {
+        output::sink_for(args.format.unwrap_or(OutputFormat::Table), Box::new(io::stdout()), &options).write_results(&results)?;
+    }
+    for path in &args.output {
+        let format = args.format.or_else(|| OutputFormat::from_extension(path)).unwrap_or(OutputFormat::Csv);
+        if is_stdout(path) {
+            output::sink_for(format, Box::new(io::stdout()), &options).write_results(&results)?;
+            continue;
+        }
+        write_results_to_file(path, format, false, producer::is_gzip(path, false), &options, &results)?;
+        eprintln!("Results saved to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Applies the same filters a scan would, plus `--country`.
+fn keep(args: &ReformatArgs, result: &ProxyResult) -> bool {
+    let country_ok = args.country.is_empty()
+        || result.country_code.as_deref().is_some_and(|code| args.country.iter().any(|wanted| wanted.eq_ignore_ascii_case(code)));
+    country_ok
+        && latency_rejection(&args.filters, None, result).is_none()
+        && args.filters.distance_rejection(result).is_none()
+        && args.filters.network_rejection(result).is_none()
+        && args.filters.only.is_none_or(|only| only == result.connection_type)
+}
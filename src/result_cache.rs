@@ -0,0 +1,83 @@
+// src/result_cache.rs
+
+use crate::ProxyResult;
+use crate::ttl_cache::TtlCache;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use subnet_scanner::geo::GeoLocationResponse;
+
+/// What a previous test of an address concluded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CachedOutcome {
+    Working(Box<CachedResult>),
+    /// Reached the test URL but was served a block page matching this signature
+    Blocked(String),
+    Failed(String),
+}
+
+/// A working result along with the parts CSV/JSON serialization of `ProxyResult` leaves
+/// out, so a cache hit still fills in `--geo-full` and `--capture-headers`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResult {
+    result: ProxyResult,
+    geo: Option<GeoLocationResponse>,
+    headers: Option<BTreeMap<String, String>>,
+}
+
+impl From<&ProxyResult> for CachedResult {
+    fn from(result: &ProxyResult) -> Self {
+        Self { result: result.clone(), geo: result.geo.clone(), headers: result.headers.clone() }
+    }
+}
+
+impl From<CachedResult> for ProxyResult {
+    fn from(cached: CachedResult) -> Self {
+        ProxyResult { geo: cached.geo, headers: cached.headers, ..cached.result }
+    }
+}
+
+/// On-disk cache of whole proxy test outcomes, so overlapping scans run close together
+/// don't retest the same address. Loaded with `--result-cache`. Entries are keyed by
+/// `ip:port` and a fingerprint of the test settings, so a run that tests differently
+/// (another protocol, URL or acceptance rule) never gets another run's verdict.
+#[derive(Debug)]
+pub struct ResultCache {
+    entries: TtlCache<CachedOutcome>,
+    fingerprint: String,
+}
+
+impl ResultCache {
+    /// Loads `path`, treating a missing file as an empty cache.
+    pub fn load(path: &Path, ttl: Duration, fingerprint: String) -> Result<Self> {
+        Ok(Self { entries: TtlCache::load(path, ttl)?, fingerprint })
+    }
+
+    pub fn get(&mut self, addr: SocketAddr) -> Option<CachedOutcome> {
+        self.entries.get(&self.key(addr))
+    }
+
+    pub fn insert(&mut self, addr: SocketAddr, outcome: CachedOutcome) {
+        self.entries.insert(self.key(addr), outcome);
+    }
+
+    /// Writes the cache back, dropping entries that have outlived the TTL.
+    pub fn save(&mut self) -> Result<()> {
+        self.entries.save()
+    }
+
+    pub fn hits(&self) -> usize {
+        self.entries.hits
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn key(&self, addr: SocketAddr) -> String {
+        format!("{} {}", addr, self.fingerprint)
+    }
+}
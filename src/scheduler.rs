@@ -0,0 +1,191 @@
+// src/scheduler.rs
+
+use crate::producer::ProxyTarget;
+use crate::{test_proxy, ProxyResult, TestConfig};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::task::{self, JoinError, JoinSet};
+
+/// Failures carry their target back for retries and `--fail-output`; boxed to keep the
+/// `Err` side small.
+pub type TestOutcome = Result<ProxyResult, Box<(ProxyTarget, anyhow::Error)>>;
+
+/// Owns the in-flight proxy tests. With `serial_per_ip`, the ports of one host are
+/// tested one at a time (the rest wait in a per-IP queue) while different hosts still
+/// run concurrently, so hosts that limit concurrent connections aren't overwhelmed.
+pub struct TestScheduler {
+    tasks: JoinSet<TestOutcome>,
+    config: Arc<TestConfig>,
+    serial_per_ip: bool,
+    in_flight: HashMap<task::Id, IpAddr>,
+    busy: HashSet<IpAddr>,
+    waiting: HashMap<IpAddr, VecDeque<ProxyTarget>>,
+    /// With `--shuffle-tests`, targets collect here and start in random order once the window fills
+    shuffle_window: Option<usize>,
+    pending: Vec<ProxyTarget>,
+    rng: StdRng,
+    /// At most this many tests run at once; the rest wait in `overflow`
+    max_concurrent: usize,
+    overflow: VecDeque<ProxyTarget>,
+}
+
+impl TestScheduler {
+    pub fn new(config: Arc<TestConfig>, serial_per_ip: bool) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            config,
+            serial_per_ip,
+            in_flight: HashMap::new(),
+            busy: HashSet::new(),
+            waiting: HashMap::new(),
+            shuffle_window: None,
+            pending: Vec::new(),
+            rng: StdRng::from_entropy(),
+            max_concurrent: usize::MAX,
+            overflow: VecDeque::new(),
+        }
+    }
+
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = max_concurrent.max(1);
+        self
+    }
+
+    /// Whether another test could start right now; the consumer stops pulling from
+    /// the producer while this is false, so backpressure reaches the scan.
+    pub fn has_capacity(&self) -> bool {
+        self.overflow.is_empty() && self.tasks.len() < self.max_concurrent
+    }
+
+    /// Buffers up to `window` targets and starts them in random order, so tests
+    /// don't walk sequential addresses the way the input arrives.
+    pub fn with_shuffle_window(mut self, window: Option<usize>) -> Self {
+        self.shuffle_window = window;
+        self
+    }
+
+    /// Shuffles with `rng` instead of fresh entropy, so `--seed` reproduces the test order.
+    pub fn with_rng(mut self, rng: StdRng) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Queues `target` for testing, buffering it first when shuffling.
+    pub fn submit(&mut self, target: ProxyTarget) {
+        match self.shuffle_window {
+            Some(window) => {
+                self.pending.push(target);
+                if self.pending.len() >= window {
+                    self.flush();
+                }
+            }
+            None => self.dispatch(target),
+        }
+    }
+
+    /// Starts everything still sitting in the shuffle window. Returns whether anything was started.
+    pub fn flush(&mut self) -> bool {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.shuffle(&mut self.rng);
+        let started = !pending.is_empty();
+        for target in pending {
+            self.dispatch(target);
+        }
+        started
+    }
+
+    /// Starts testing `target`, or queues it behind a test already running on the same IP.
+    fn dispatch(&mut self, target: ProxyTarget) {
+        let ip = target.addr.ip();
+        if self.serial_per_ip && !self.busy.insert(ip) {
+            self.waiting.entry(ip).or_default().push_back(target);
+            return;
+        }
+        if self.tasks.len() >= self.max_concurrent {
+            self.overflow.push_back(target);
+            return;
+        }
+        self.spawn(target);
+    }
+
+    fn spawn(&mut self, target: ProxyTarget) {
+        let ip = target.addr.ip();
+        let handle = self.tasks.spawn(test_proxy(target, self.config.clone()));
+        if self.serial_per_ip {
+            self.in_flight.insert(handle.id(), ip);
+        }
+    }
+
+    /// Waits for the next test to finish, starting the next queued port on that IP.
+    pub async fn join_next(&mut self) -> Option<Result<TestOutcome, JoinError>> {
+        let res = self.tasks.join_next_with_id().await?;
+        let id = match &res {
+            Ok((id, _)) => *id,
+            Err(e) => e.id(),
+        };
+        self.release(id);
+        while self.tasks.len() < self.max_concurrent {
+            let Some(next) = self.overflow.pop_front() else {
+                break;
+            };
+            self.spawn(next);
+        }
+        Some(res.map(|(_, outcome)| outcome))
+    }
+
+    fn release(&mut self, id: task::Id) {
+        let Some(ip) = self.in_flight.remove(&id) else {
+            return;
+        };
+        match self.waiting.get_mut(&ip).and_then(VecDeque::pop_front) {
+            Some(next) => self.spawn(next),
+            None => {
+                self.waiting.remove(&ip);
+                self.busy.remove(&ip);
+            }
+        }
+    }
+
+    pub fn abort_all(&mut self) {
+        self.pending.clear();
+        self.overflow.clear();
+        self.waiting.clear();
+        self.tasks.abort_all();
+    }
+}
+
+// --- Concurrency Limit ---
+
+/// Descriptors kept back for stdio, input/output files, DNS and the scan threads' sockets.
+#[cfg(unix)]
+const FD_RESERVE: u64 = 64;
+
+/// Picks a default `--max-concurrent` from the open-file limit, first raising the soft
+/// limit toward the hard one. Each test can hold two sockets (the proxied request and a
+/// `--timings` connect), so the cap is half of what is left after the reserve.
+/// Returns the cap and a warning when the limit is uncomfortably low.
+#[cfg(unix)]
+pub fn fd_limited_concurrency() -> (usize, Option<String>) {
+    let Ok((soft, hard)) = rlimit::Resource::NOFILE.get() else {
+        return (usize::MAX, None);
+    };
+    let soft = if soft < hard { rlimit::increase_nofile_limit(hard).unwrap_or(soft) } else { soft };
+    let cap = (soft.saturating_sub(FD_RESERVE) / 2).clamp(1, usize::MAX as u64) as usize;
+    let warning = (cap < 128).then(|| {
+        format!("Open-file limit is only {}; capping concurrent tests at {} (raise it with `ulimit -n`)", soft, cap)
+    });
+    (cap, warning)
+}
+
+#[cfg(not(unix))]
+pub fn fd_limited_concurrency() -> (usize, Option<String>) {
+    (usize::MAX, None)
+}
@@ -0,0 +1,63 @@
+// src/ttl_cache.rs
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Stamped<V> {
+    value: V,
+    /// Unix timestamp (seconds) of when the value was stored
+    stored_at: u64,
+}
+
+/// A JSON file of values keyed by string that expire `ttl` after they were stored.
+/// Backs `--geo-cache` and `--result-cache`; loaded at startup and saved at exit.
+#[derive(Debug)]
+pub struct TtlCache<V> {
+    path: PathBuf,
+    ttl: Duration,
+    entries: HashMap<String, Stamped<V>>,
+    pub hits: usize,
+}
+
+impl<V: Clone + Serialize + DeserializeOwned> TtlCache<V> {
+    /// Loads `path`, treating a missing file as an empty cache.
+    pub fn load(path: &Path, ttl: Duration) -> Result<Self> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path: path.to_path_buf(), ttl, entries, hits: 0 })
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.get(key).filter(|entry| now_secs().saturating_sub(entry.stored_at) < self.ttl.as_secs())?;
+        self.hits += 1;
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        self.entries.insert(key, Stamped { value, stored_at: now_secs() });
+    }
+
+    /// Writes the cache back, dropping entries that have outlived the TTL.
+    pub fn save(&mut self) -> Result<()> {
+        let now = now_secs();
+        let ttl = self.ttl.as_secs();
+        self.entries.retain(|_, entry| now.saturating_sub(entry.stored_at) < ttl);
+        crate::output::write_atomically(&self.path, |writer| Ok(serde_json::to_writer(writer, &self.entries)?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
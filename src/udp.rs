@@ -0,0 +1,134 @@
+// src/udp.rs
+
+use crate::producer::Credentials;
+use anyhow::{bail, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use subnet_scanner::nat64::Nat64Prefix;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Public resolver the test datagram is relayed to.
+const DNS_PROBE_TARGET: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 53);
+
+/// Asks a SOCKS5 proxy for a UDP relay and pushes a DNS query through it.
+/// `Ok(false)` means the proxy refused UDP ASSOCIATE or never relayed an answer;
+/// `Err` is reserved for the control connection itself failing.
+pub async fn socks5_udp_supported(proxy: SocketAddr, credentials: Option<&Credentials>, timeout: Duration, nat64: Option<Nat64Prefix>) -> Result<bool> {
+    let mut control = tokio::time::timeout(timeout, TcpStream::connect(proxy)).await??;
+    tokio::time::timeout(timeout, negotiate(&mut control, credentials)).await??;
+
+    // Ask for a relay; 0.0.0.0:0 means "I don't know my source address yet"
+    control.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    let relay = match tokio::time::timeout(timeout, read_reply(&mut control)).await? {
+        Ok(relay) => relay,
+        Err(_) => return Ok(false),
+    };
+    // Many servers answer with an unspecified bind address, meaning "same host as the proxy"
+    let relay = if relay.ip().is_unspecified() { SocketAddr::new(proxy.ip(), relay.port()) } else { relay };
+    let relay = nat64.map_or(relay, |prefix| prefix.synthesize(relay));
+
+    let bind: SocketAddr = match relay {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(relay).await?;
+
+    let txid: u16 = std::process::id() as u16 ^ proxy.port();
+    socket.send(&encapsulate(DNS_PROBE_TARGET, &dns_query(txid))).await?;
+
+    let mut buf = [0u8; 1500];
+    let answered = match tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+        // 10-byte SOCKS UDP header for an IPv4 source, then the DNS header's transaction ID
+        Ok(Ok(len)) => len >= 12 && buf[3] == 0x01 && u16::from_be_bytes([buf[10], buf[11]]) == txid,
+        _ => false,
+    };
+    // The relay only lives as long as the control connection, so hold it until now
+    drop(control);
+    Ok(answered)
+}
+
+/// Method selection, plus RFC 1929 username/password auth when the proxy asks for it.
+async fn negotiate(control: &mut TcpStream, credentials: Option<&Credentials>) -> Result<()> {
+    let greeting: &[u8] = if credentials.is_some() { &[0x05, 0x02, 0x00, 0x02] } else { &[0x05, 0x01, 0x00] };
+    control.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    control.read_exact(&mut choice).await?;
+
+    match (choice, credentials) {
+        ([0x05, 0x00], _) => Ok(()),
+        ([0x05, 0x02], Some(credentials)) => {
+            let (user, pass) = (credentials.username.as_bytes(), credentials.password.as_bytes());
+            if user.len() > 255 || pass.len() > 255 {
+                bail!("SOCKS5 credentials are longer than 255 bytes");
+            }
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user);
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass);
+            control.write_all(&request).await?;
+            let mut status = [0u8; 2];
+            control.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                bail!("SOCKS5 authentication rejected");
+            }
+            Ok(())
+        }
+        _ => bail!("SOCKS5 proxy offered no usable auth method"),
+    }
+}
+
+/// Reads a SOCKS5 reply and returns its bound address.
+async fn read_reply(control: &mut TcpStream) -> Result<SocketAddr> {
+    let mut head = [0u8; 4];
+    control.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        bail!("SOCKS5 UDP ASSOCIATE refused (reply {:#04x})", head[1]);
+    }
+    let ip = match head[3] {
+        0x01 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        0x04 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        atyp => bail!("Unsupported SOCKS5 address type {:#04x} in reply", atyp),
+    };
+    let port = control.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Wraps a payload in the SOCKS5 UDP request header addressed to `target`.
+fn encapsulate(target: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x00, 0x00, 0x00];
+    match target.ip() {
+        IpAddr::V4(v4) => {
+            datagram.push(0x01);
+            datagram.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            datagram.push(0x04);
+            datagram.extend_from_slice(&v6.octets());
+        }
+    }
+    datagram.extend_from_slice(&target.port().to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// A minimal recursive `A example.com` query.
+fn dns_query(txid: u16) -> Vec<u8> {
+    let mut query = txid.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in ["example", "com"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+    query
+}